@@ -23,7 +23,7 @@ in
         type = lib.types.str;
         default = "[::]:8080";
         description = ''
-          Socket address to listen on.
+          Socket address to listen on, `HOST:PORT` or `unix:PATH`.
         '';
       };
       log = lib.mkOption {
@@ -0,0 +1,266 @@
+//! TOML configuration file
+//!
+//! Tables `server` and `push` hold values of options of the subcommands, keyed by long option names.
+//! Values in the file become defaults of the options, so the command line overrides them.
+//! Flags set to `true` in the file get negated flags on the command line, e.g. `--no-metrics`,
+//! or `--ssl` for `no-ssl`.
+//!
+//! The file is only read by the subcommands in `SECTIONS` and `config`.
+//!
+//! ```toml
+//! [server]
+//! listen = "unix:/run/oranc/oranc.sock"
+//! upstream = ["https://cache.nixos.org,mode=proxy,priority=10"]
+//! upstream-timeout = 3
+//! metrics = true
+//! ```
+
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{Arg, ArgAction, Command, CommandFactory, FromArgMatches, builder::ArgPredicate};
+
+use crate::{error::Error, options::Options};
+
+pub const CONFIG_ENV: &str = "ORANC_CONFIG";
+
+/// Subcommands configurable in the file
+const SECTIONS: [&str; 2] = ["server", "push"];
+
+/// Subcommands accepting `--config`
+const READERS: [&str; 3] = ["server", "push", "config"];
+
+/// Parses command line options with the configuration file applied
+pub fn parse_options() -> Result<Options, Error> {
+    let args: Vec<OsString> = env::args_os().collect();
+    let mut command = Options::command();
+    // `config check` loads the file itself, `ORANC_CONFIG` is not read for an explicit path
+    if let Some(path) = find_path(&args, &SECTIONS) {
+        command = apply(command, &path, &load(&path)?)?;
+    }
+    let matches = command.get_matches_from(args);
+    Ok(Options::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+}
+
+/// Path of the configuration file, from `--config` or `ORANC_CONFIG`
+///
+/// Subcommands other than `readers` do not read the file.
+fn find_path(args: &[OsString], readers: &[&str]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1);
+    let subcommand = args
+        .by_ref()
+        .find(|arg| !arg.to_string_lossy().starts_with('-'))?;
+    if !readers.contains(&subcommand.to_string_lossy().as_ref()) {
+        return None;
+    }
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    env::var_os(CONFIG_ENV).map(PathBuf::from)
+}
+
+fn load(path: &Path) -> Result<toml::Table, Error> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| Error::InvalidConfig(path.to_owned(), e.to_string()))?;
+    content
+        .parse()
+        .map_err(|e: toml::de::Error| Error::InvalidConfig(path.to_owned(), e.to_string()))
+}
+
+/// Sets values in the configuration as defaults of options
+fn apply(mut command: Command, path: &Path, config: &toml::Table) -> Result<Command, Error> {
+    let invalid = |message: String| Error::InvalidConfig(path.to_owned(), message);
+    for (section, table) in config {
+        if !SECTIONS.contains(&section.as_str()) {
+            return Err(invalid(format!("unknown section '{section}'")));
+        }
+        let table = table
+            .as_table()
+            .ok_or_else(|| invalid(format!("'{section}' is not a table")))?;
+        let subcommand = command
+            .find_subcommand(section)
+            .expect("configurable subcommand")
+            .clone();
+        for (key, value) in table {
+            let long = key.replace('_', "-");
+            let arg = subcommand
+                .get_arguments()
+                .find(|a| a.get_long() == Some(long.as_str()) && long != "config")
+                .ok_or_else(|| invalid(format!("unknown option '{section}.{key}'")))?;
+            let id = arg.get_id().clone();
+            let values = option_values(value)
+                .ok_or_else(|| invalid(format!("invalid value of '{section}.{key}'")))?;
+            let negated = match (arg.get_action(), value) {
+                (ArgAction::SetTrue, toml::Value::Boolean(true)) => Some(negated_flag(&long)),
+                _ => None,
+            };
+            command = command.mut_subcommand(section, |s| match negated {
+                Some(negated) => s
+                    .mut_arg(&id, |a| {
+                        a.default_value("true").default_value_if(
+                            &negated,
+                            ArgPredicate::IsPresent,
+                            "false",
+                        )
+                    })
+                    .arg(
+                        Arg::new(&negated)
+                            .long(&negated)
+                            .action(ArgAction::SetTrue)
+                            .conflicts_with(&id)
+                            .help(format!(
+                                "override `{long} = true` in the configuration file"
+                            )),
+                    ),
+                None => s.mut_arg(id, |a| a.required(false).default_values(values)),
+            });
+        }
+    }
+    Ok(command)
+}
+
+/// Long name of the flag turning off `long`
+fn negated_flag(long: &str) -> String {
+    match long.strip_prefix("no-") {
+        Some(positive) => positive.to_owned(),
+        None => format!("no-{long}"),
+    }
+}
+
+/// Command line values of a configuration value
+fn option_values(value: &toml::Value) -> Option<Vec<String>> {
+    match value {
+        toml::Value::String(s) => Some(vec![s.clone()]),
+        toml::Value::Integer(i) => Some(vec![i.to_string()]),
+        toml::Value::Float(f) => Some(vec![f.to_string()]),
+        toml::Value::Boolean(b) => Some(vec![b.to_string()]),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|v| match v {
+                toml::Value::Array(_) | toml::Value::Table(_) => None,
+                v => option_values(v).and_then(|mut v| v.pop()),
+            })
+            .collect(),
+        toml::Value::Datetime(_) | toml::Value::Table(_) => None,
+    }
+}
+
+/// Validates the configuration file, `oranc config check`
+pub fn check(path: Option<PathBuf>) -> Result<(), Error> {
+    let path = path
+        .or_else(|| find_path(&env::args_os().collect::<Vec<_>>(), &READERS))
+        .ok_or(Error::NoConfig)?;
+    let config = load(&path)?;
+    let command = apply(Options::command(), &path, &config)?;
+    for section in config.keys() {
+        // values are parsed like command line values, options missing in the file are fine
+        let command = command
+            .clone()
+            .mut_subcommand(section, |s| s.mut_args(|a| a.required(false)));
+        command
+            .try_get_matches_from(["oranc", section.as_str()])
+            .map_err(|e| {
+                // only the first line, without usage and help hints
+                let rendered = e.render().to_string();
+                let message = rendered.lines().next().unwrap_or_default();
+                let message = message.strip_prefix("error: ").unwrap_or(message);
+                Error::InvalidConfig(path.clone(), format!("[{section}] {message}"))
+            })?;
+    }
+    println!("{}: ok", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(config: &str, args: &[&str]) -> Result<Options, Error> {
+        let config = config.parse().unwrap();
+        let command = apply(Options::command(), Path::new("test.toml"), &config)?;
+        let matches = command
+            .try_get_matches_from(args)
+            .map_err(|e| Error::InvalidConfig(PathBuf::from("test.toml"), e.to_string()))?;
+        Ok(Options::from_arg_matches(&matches).unwrap())
+    }
+
+    #[test]
+    fn test_apply() {
+        let config = r#"
+            [server]
+            max-retry = 5
+            no_ssl = true
+            upstream = ["https://a.example", "https://b.example,priority=1"]
+        "#;
+        let server = |args| match options(config, args).unwrap().command {
+            crate::options::Commands::Server(o) => o,
+            _ => unreachable!(),
+        };
+        let o = server(&["oranc", "server"]);
+        assert_eq!(o.max_retry, 5);
+        assert!(o.no_ssl);
+        assert_eq!(o.upstream.len(), 2);
+        let o = server(&[
+            "oranc",
+            "server",
+            "--max-retry",
+            "1",
+            "--upstream",
+            "https://c.example",
+        ]);
+        assert_eq!(o.max_retry, 1);
+        assert_eq!(o.upstream.len(), 1);
+
+        // flags from the file are turned off by negated flags
+        let o = server(&["oranc", "server", "--ssl"]);
+        assert!(!o.no_ssl);
+        let config = "[server]\nmetrics = true";
+        let metrics = |args| match options(config, args).unwrap().command {
+            crate::options::Commands::Server(o) => o.metrics,
+            _ => unreachable!(),
+        };
+        assert!(metrics(&["oranc", "server"]));
+        assert!(!metrics(&["oranc", "server", "--no-metrics"]));
+        assert!(options(config, &["oranc", "server", "--metrics", "--no-metrics"]).is_err());
+
+        assert!(options("[server]\nunknown = 1", &["oranc", "server"]).is_err());
+        assert!(options("[server]\nconfig = \"a.toml\"", &["oranc", "server"]).is_err());
+        assert!(options("[server]\nmax-retry = \"x\"", &["oranc", "server"]).is_err());
+    }
+
+    #[test]
+    fn test_find_path() {
+        let args = |a: &[&str]| a.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            find_path(&args(&["oranc", "server", "--config", "a.toml"]), &READERS),
+            Some(PathBuf::from("a.toml"))
+        );
+        assert_eq!(
+            find_path(&args(&["oranc", "server", "--config=b.toml"]), &READERS),
+            Some(PathBuf::from("b.toml"))
+        );
+        assert_eq!(
+            find_path(
+                &args(&["oranc", "tag", "encode", "--config", "c.toml"]),
+                &READERS
+            ),
+            None
+        );
+        // `config check` reads its file itself
+        assert_eq!(
+            find_path(&args(&["oranc", "config", "check", "d.toml"]), &SECTIONS),
+            None
+        );
+    }
+}
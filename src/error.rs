@@ -1,16 +1,21 @@
-use std::{env::VarError, ffi::OsString, string::FromUtf8Error};
+use std::{env::VarError, ffi::OsString, path::PathBuf, string::FromUtf8Error, sync::Arc};
 
 use axum::{
     extract::rejection::{PathRejection, QueryRejection},
     response::{IntoResponse, Response},
 };
-use http::{HeaderName, StatusCode, header::ToStrError};
-use oci_client::errors::OciDistributionError;
+use http::{
+    HeaderName, HeaderValue, StatusCode,
+    header::{CONTENT_TYPE, RETRY_AFTER, ToStrError, WWW_AUTHENTICATE},
+};
+use oci_client::errors::{OciDistributionError, OciErrorCode};
 use reqwest::Url;
 
-use crate::registry::OciLocation;
+use crate::registry::{OciLocation, is_unauthorized};
+use crate::xml::xml_escape;
 
-const NO_SUCH_KEY_RESPONSE_BODY: &str = "<Error><Code>NoSuchKey</Code></Error>";
+// ask curl based clients (Nix) to retry with credentials from netrc
+const AUTHENTICATE_CHALLENGE: HeaderValue = HeaderValue::from_static("Basic realm=\"oranc\"");
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -21,6 +26,14 @@ pub enum Error {
     Reqwest(#[from] reqwest::Error),
     #[error("io error: {0:?}")]
     Io(#[from] std::io::Error),
+    #[error("prometheus error: {0}")]
+    Prometheus(#[from] prometheus::Error),
+    #[error("invalid configuration file '{0}': {1}")]
+    InvalidConfig(PathBuf, String),
+    #[error("no configuration file, pass a path, `--config` or set `ORANC_CONFIG`")]
+    NoConfig,
+    #[error("failed to bind '{0}': {1}")]
+    Bind(String, std::io::Error),
     #[error("rusqlite error: {0}")]
     Rusqlite(#[from] rusqlite::Error),
     #[error("duplicated path info: {0}")]
@@ -45,6 +58,20 @@ pub enum Error {
     PathRejection(#[from] PathRejection),
     #[error("upstream url '{0}' can not be base")]
     UpstreamCanNotBeBase(Url),
+    #[error("blob digest mismatch: expected = {expected}, actual = {actual}")]
+    BlobDigestMismatch { expected: String, actual: String },
+    #[error("{0}")]
+    Shared(Arc<Error>),
+    #[error("mirrored nar does not match narinfo: {0}")]
+    MirrorMismatch(String),
+    #[error("invalid s3 credentials file '{0}', line {1}")]
+    InvalidS3Credentials(PathBuf, usize),
+    #[error("invalid upload location from registry: {0}")]
+    InvalidUploadLocation(String),
+    #[error("invalid registry credentials entry for '{0}', expecting REGISTRY=USERNAME:PASSWORD")]
+    InvalidRegistryCredentials(String),
+    #[error("unable to read environment variable `ORANC_REGISTRY_CREDENTIALS`: {0}")]
+    InvalidRegistryCredentialsEnv(VarError),
 
     // client side errors
     #[error("decode error: {0}")]
@@ -59,18 +86,56 @@ pub enum Error {
     HeaderToStr(HeaderName, ToStrError),
     #[error("invalid authorization header: {0}")]
     InvalidAuthorization(String),
+    #[error("unknown access key id: {0}")]
+    InvalidAccessKeyId(String),
+    #[error("request signature does not match")]
+    SignatureDoesNotMatch,
+    #[error("request time too skewed")]
+    RequestTimeTooSkewed,
+    #[error("anonymous write to {0} is not allowed")]
+    AnonymousWrite(OciLocation),
+    #[error("failed to read request body: {0}")]
+    Body(axum::Error),
+    #[error("request body does not match x-amz-content-sha256")]
+    XAmzContentSHA256Mismatch,
+    #[error("registry responded {status}: {message}")]
+    RegistryResponse {
+        status: StatusCode,
+        retry_after: Option<HeaderValue>,
+        message: String,
+    },
+    #[error("no such multipart upload: {0}")]
+    NoSuchUpload(String),
+    #[error("too many multipart uploads in progress: {0}")]
+    MultipartUploadLimit(&'static str),
+    #[error("invalid part number: {0}")]
+    InvalidPartNumber(String),
+    #[error("part {0} not uploaded or etag not match")]
+    InvalidPart(u32),
+    #[error("parts are not in ascending order")]
+    InvalidPartOrder,
+    #[error("malformed xml: {0}")]
+    MalformedXml(String),
+    #[error("invalid list parameter {0}: {1}")]
+    InvalidListParameter(&'static str, String),
+    #[error("not implemented: {0}")]
+    NotImplemented(String),
     #[error("oci distribution error: {0}")]
     OciDistribution(#[from] OciDistributionError),
     #[error("invalid image layer count: {0}")]
     InvalidLayerCount(usize),
     #[error("invalid image layer media type: {0}")]
     InvalidLayerMediaType(String),
+    #[error("invalid image layer size: {0}")]
+    InvalidLayerSize(i64),
     #[error("lack of layer annotations")]
     NoLayerAnnotations,
     #[error("lack of layer annotation key: {0}")]
     NoLayerAnnotationKey(String),
     #[error("reference not found: {0}")]
     ReferenceNotFound(OciLocation),
+    #[error("precondition failed: {0}")]
+    PreconditionFailed(OciLocation),
     #[error("ill-formed path: {0}")]
     IllFormedPath(String),
     #[error("invalid query string: {0}")]
@@ -81,8 +146,12 @@ pub enum Error {
     NoPathInfo(String),
     #[error("invalid store path: {0}")]
     InvalidStorePath(String),
+    #[error("invalid narinfo: {0}")]
+    InvalidNarInfo(String),
     #[error("invalid signing key: {0}")]
     InvalidSigningKey(String),
+    #[error("invalid public key: {0}")]
+    InvalidPublicKey(String),
     #[error("invalid max retry number: {0}")]
     InvalidMaxRetry(usize),
     #[error("nix db folder '{0}' is not writable")]
@@ -99,20 +168,54 @@ pub enum Error {
     },
 }
 
+impl From<axum::Error> for Error {
+    fn from(e: axum::Error) -> Self {
+        // errors of wrapped request bodies, e.g. a payload hash mismatch
+        match e.into_inner().downcast::<Error>() {
+            Ok(e) => *e,
+            Err(e) => match e.downcast::<axum::Error>() {
+                Ok(e) => Error::from(*e),
+                Err(e) => Error::Body(axum::Error::new(e)),
+            },
+        }
+    }
+}
+
+/// Message of the error a response is built from, logged by the server
+#[derive(Debug, Clone)]
+pub struct ErrorMessage(pub String);
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        log::info!("report error: {self}");
         let code = self.code();
-        let body = if let Error::ReferenceNotFound(_) = self {
-            NO_SUCH_KEY_RESPONSE_BODY.to_string() // s3 client will parse the body
-        } else if code.is_client_error() {
+        let message = if code.is_client_error() {
             self.to_string()
         } else {
             code.canonical_reason()
                 .unwrap_or("unknown error")
                 .to_owned()
         };
-        (code, body).into_response()
+        // s3 clients will parse the body
+        let body = format!(
+            "<Error><Code>{}</Code><Message>{}</Message></Error>",
+            self.s3_code(),
+            xml_escape(&message)
+        );
+        let mut response = (code, [(CONTENT_TYPE, "application/xml")], body).into_response();
+        if code == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, AUTHENTICATE_CHALLENGE);
+        }
+        if let Some(retry_after) = self.retry_after() {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, retry_after.clone());
+        }
+        response
+            .extensions_mut()
+            .insert(ErrorMessage(self.to_string()));
+        response
     }
 }
 
@@ -120,8 +223,12 @@ impl Error {
     pub fn code(&self) -> StatusCode {
         match self {
             Error::Http(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            Error::Reqwest(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Reqwest(e) => reqwest_code(e),
             Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Bind(_, _) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::InvalidConfig(_, _) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::NoConfig => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Prometheus(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::Rusqlite(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::DuplicatedPathInfo(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::InvalidSignature(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -129,11 +236,22 @@ impl Error {
             Error::Join(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::InvalidNarSize(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::NarSizeNotMatch(_, _) => StatusCode::INTERNAL_SERVER_ERROR,
-            Error::RetryAllFails(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            // report the last failure, earlier ones are likely the same
+            Error::RetryAllFails(errors) => errors
+                .last()
+                .map(Error::code)
+                .unwrap_or(StatusCode::BAD_GATEWAY),
             Error::PushFailed => StatusCode::INTERNAL_SERVER_ERROR,
             Error::Nar(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::PathRejection(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::UpstreamCanNotBeBase(_) => StatusCode::BAD_REQUEST,
+            Error::BlobDigestMismatch { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Shared(e) => e.code(),
+            Error::MirrorMismatch(_) => StatusCode::BAD_GATEWAY,
+            Error::InvalidS3Credentials(_, _) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::InvalidUploadLocation(_) => StatusCode::BAD_GATEWAY,
+            Error::InvalidRegistryCredentials(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::InvalidRegistryCredentialsEnv(_) => StatusCode::INTERNAL_SERVER_ERROR,
 
             Error::Decode(_) => StatusCode::BAD_REQUEST,
             Error::TagToKey(_) => StatusCode::BAD_REQUEST,
@@ -141,17 +259,36 @@ impl Error {
             Error::FromUtf8(_) => StatusCode::BAD_REQUEST,
             Error::HeaderToStr(_, _) => StatusCode::BAD_REQUEST,
             Error::InvalidAuthorization(_) => StatusCode::BAD_REQUEST,
-            Error::OciDistribution(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidAccessKeyId(_) => StatusCode::FORBIDDEN,
+            Error::SignatureDoesNotMatch => StatusCode::FORBIDDEN,
+            Error::RequestTimeTooSkewed => StatusCode::FORBIDDEN,
+            Error::AnonymousWrite(_) => StatusCode::UNAUTHORIZED,
+            Error::Body(_) => StatusCode::BAD_REQUEST,
+            Error::XAmzContentSHA256Mismatch => StatusCode::BAD_REQUEST,
+            Error::RegistryResponse { status, .. } => registry_status_code(status.as_u16()),
+            Error::NoSuchUpload(_) => StatusCode::NOT_FOUND,
+            Error::MultipartUploadLimit(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::InvalidPartNumber(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidPart(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidPartOrder => StatusCode::BAD_REQUEST,
+            Error::MalformedXml(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidListParameter(_, _) => StatusCode::BAD_REQUEST,
+            Error::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            Error::OciDistribution(e) => oci_distribution_code(e),
             Error::InvalidLayerCount(_) => StatusCode::BAD_REQUEST,
             Error::InvalidLayerMediaType(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidLayerSize(_) => StatusCode::BAD_REQUEST,
             Error::NoLayerAnnotations => StatusCode::BAD_REQUEST,
             Error::NoLayerAnnotationKey(_) => StatusCode::BAD_REQUEST,
             Error::ReferenceNotFound(_) => StatusCode::NOT_FOUND,
+            Error::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
             Error::IllFormedPath(_) => StatusCode::NOT_FOUND,
             Error::InvalidQueryString(_) => StatusCode::BAD_REQUEST,
             Error::InvalidOsString(_) => StatusCode::BAD_REQUEST,
             Error::NoPathInfo(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidNarInfo(_) => StatusCode::BAD_GATEWAY,
             Error::InvalidStorePath(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidPublicKey(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::InvalidSigningKey(_) => StatusCode::BAD_REQUEST,
             Error::InvalidMaxRetry(_) => StatusCode::BAD_REQUEST,
             Error::NixDbFolderNotWritable(_) => StatusCode::BAD_REQUEST,
@@ -161,3 +298,120 @@ impl Error {
         }
     }
 }
+
+impl Error {
+    /// Error code in s3 error responses
+    pub fn s3_code(&self) -> &'static str {
+        match self {
+            Error::ReferenceNotFound(_) => "NoSuchKey",
+            Error::InvalidAccessKeyId(_) => "InvalidAccessKeyId",
+            Error::SignatureDoesNotMatch => "SignatureDoesNotMatch",
+            Error::RequestTimeTooSkewed => "RequestTimeTooSkewed",
+            Error::XAmzContentSHA256Mismatch => "XAmzContentSHA256Mismatch",
+            Error::NoSuchUpload(_) => "NoSuchUpload",
+            Error::MultipartUploadLimit(_) => "SlowDown",
+            Error::InvalidPartNumber(_) => "InvalidArgument",
+            Error::InvalidPart(_) => "InvalidPart",
+            Error::InvalidPartOrder => "InvalidPartOrder",
+            Error::MalformedXml(_) => "MalformedXML",
+            Error::InvalidListParameter(_, _) => "InvalidArgument",
+            Error::PreconditionFailed(_) => "PreconditionFailed",
+            Error::NotImplemented(_) => "NotImplemented",
+            Error::Shared(e) => e.s3_code(),
+            _ => match self.code() {
+                StatusCode::NOT_FOUND => "NoSuchKey",
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => "AccessDenied",
+                StatusCode::TOO_MANY_REQUESTS => "SlowDown",
+                StatusCode::SERVICE_UNAVAILABLE => "ServiceUnavailable",
+                code if code.is_client_error() => "InvalidRequest",
+                _ => "InternalError",
+            },
+        }
+    }
+
+    /// `Retry-After` sent by the registry, forwarded to clients
+    pub fn retry_after(&self) -> Option<&HeaderValue> {
+        match self {
+            Error::RegistryResponse { retry_after, .. } => retry_after.as_ref(),
+            Error::RetryAllFails(errors) => errors.last().and_then(Error::retry_after),
+            Error::Shared(e) => e.retry_after(),
+            _ => None,
+        }
+    }
+}
+
+/// Classifies errors from the OCI registry
+fn oci_distribution_code(e: &OciDistributionError) -> StatusCode {
+    if is_unauthorized(e) {
+        return StatusCode::UNAUTHORIZED;
+    }
+    match e {
+        OciDistributionError::ImageManifestNotFoundError(_) => StatusCode::NOT_FOUND,
+        OciDistributionError::RegistryError { envelope, .. } => envelope
+            .errors
+            .iter()
+            .map(|e| oci_error_code(&e.code))
+            .max_by_key(|c| oci_error_code_priority(*c))
+            .unwrap_or(StatusCode::BAD_GATEWAY),
+        OciDistributionError::ServerError { code, .. } => registry_status_code(*code),
+        OciDistributionError::RequestError(e) => reqwest_code(e),
+        OciDistributionError::PushNoDataError | OciDistributionError::PushLayerNoDataError => {
+            StatusCode::BAD_REQUEST
+        }
+        // the registry returned something we can not understand
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+fn oci_error_code(code: &OciErrorCode) -> StatusCode {
+    match code {
+        OciErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+        OciErrorCode::Denied => StatusCode::FORBIDDEN,
+        OciErrorCode::Toomanyrequests => StatusCode::TOO_MANY_REQUESTS,
+        OciErrorCode::BlobUnknown
+        | OciErrorCode::BlobUploadUnknown
+        | OciErrorCode::ManifestUnknown
+        | OciErrorCode::NameUnknown
+        | OciErrorCode::NotFound => StatusCode::NOT_FOUND,
+        OciErrorCode::Unsupported => StatusCode::METHOD_NOT_ALLOWED,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// When the registry reports multiple errors, report the one the client can act on
+fn oci_error_code_priority(code: StatusCode) -> u8 {
+    match code {
+        StatusCode::TOO_MANY_REQUESTS => 4,
+        StatusCode::UNAUTHORIZED => 3,
+        StatusCode::FORBIDDEN => 2,
+        StatusCode::NOT_FOUND => 1,
+        _ => 0,
+    }
+}
+
+/// Classifies failed requests to the registry or upstreams
+fn reqwest_code(e: &reqwest::Error) -> StatusCode {
+    if let Some(status) = e.status() {
+        registry_status_code(status.as_u16())
+    } else if e.is_timeout() {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::BAD_GATEWAY
+    }
+}
+
+/// Maps plain HTTP status codes from the registry
+fn registry_status_code(code: u16) -> StatusCode {
+    match StatusCode::from_u16(code) {
+        Ok(
+            c @ (StatusCode::UNAUTHORIZED
+            | StatusCode::FORBIDDEN
+            | StatusCode::NOT_FOUND
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT),
+        ) => c,
+        // other failures are problems of the registry, not of the client
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
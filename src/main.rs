@@ -1,23 +1,32 @@
+pub mod config;
 pub mod convert;
 pub mod error;
 pub mod key;
+pub mod metrics;
 pub mod nix;
 pub mod options;
 pub mod push;
 pub mod registry;
 pub mod server;
+pub mod xml;
 
-use clap::{CommandFactory, Parser};
+use clap::CommandFactory;
 
 use error::Error;
-use options::{Commands, Options};
+use options::{Commands, ConfigCommands, Options};
 use pretty_env_logger::formatted_builder;
 
 #[tokio::main]
 async fn main() {
     init_logger();
 
-    let options = options::Options::parse();
+    let options = match config::parse_options() {
+        Ok(o) => o,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
     log::debug!("options = {:#?}", options);
     if let Err(e) = main_result(options).await {
         log::error!("{}", e);
@@ -37,12 +46,13 @@ fn init_logger() {
 
 async fn main_result(options: Options) -> Result<(), Error> {
     match options.command {
-        Commands::Server(server_options) => server::server_main(server_options).await?,
+        Commands::Server(server_options) => server::server_main(*server_options).await?,
         Commands::Tag(key_commands) => key::key_main(key_commands).await?,
         Commands::Push(push_options) => push::push_main(push_options).await?,
         Commands::Completion(completion_options) => {
             generate_shell_completions(completion_options).await?
         }
+        Commands::Config(ConfigCommands::Check { path, .. }) => config::check(path)?,
     }
     Ok(())
 }
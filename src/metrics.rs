@@ -0,0 +1,148 @@
+//! Prometheus metrics of the server
+
+use std::time::Instant;
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::TryStreamExt;
+use http::{Method, header};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::error::Error;
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics::new().unwrap());
+
+pub struct Metrics {
+    registry: Registry,
+    /// Labels: method, status
+    pub http_requests: IntCounterVec,
+    /// Labels: method
+    pub http_request_duration: HistogramVec,
+    pub response_bytes: IntCounter,
+    /// Labels: result, one of `hit`, `miss`, `cached_miss` and `error`
+    pub upstream_checks: IntCounterVec,
+    /// Labels: operation, one of `manifest` and `blob`
+    pub registry_request_duration: HistogramVec,
+    /// Labels: operation, one of `manifest` and `push`
+    pub registry_retries: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("oranc".to_owned()), None)?;
+        let metrics = Metrics {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests by method and status"),
+                &["method", "status"],
+            )?,
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "time until response headers are sent",
+                ),
+                &["method"],
+            )?,
+            response_bytes: IntCounter::new("response_bytes_total", "bytes of response bodies")?,
+            upstream_checks: IntCounterVec::new(
+                Opts::new("upstream_checks_total", "upstream checks by result"),
+                &["result"],
+            )?,
+            registry_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "registry_request_duration_seconds",
+                    "time until registry responses are received",
+                ),
+                &["operation"],
+            )?,
+            registry_retries: IntCounterVec::new(
+                Opts::new(
+                    "registry_retries_total",
+                    "failed registry requests to be retried",
+                ),
+                &["operation"],
+            )?,
+            registry,
+        };
+        metrics
+            .registry
+            .register(Box::new(metrics.http_requests.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.http_request_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.response_bytes.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.upstream_checks.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.registry_request_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.registry_retries.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Observes the time since `start` in a registry operation
+    pub fn observe_registry(&self, operation: &str, start: Instant) {
+        self.registry_request_duration
+            .with_label_values(&[operation])
+            .observe(start.elapsed().as_secs_f64());
+    }
+}
+
+/// Handler of `/metrics`
+pub async fn serve() -> Result<Response<Body>, Error> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&METRICS.registry.gather(), &mut buffer)?;
+    Ok(([(header::CONTENT_TYPE, encoder.format_type())], buffer).into_response())
+}
+
+/// Label of a request method, clients may send any method and labels are unbounded
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::PUT => "PUT",
+        Method::POST => "POST",
+        Method::DELETE => "DELETE",
+        _ => "other",
+    }
+}
+
+/// Middleware counting requests and response bytes
+pub async fn track(request: Request, next: Next) -> Response<Body> {
+    let method = method_label(request.method());
+    let start = Instant::now();
+    let response = next.run(request).await;
+    METRICS
+        .http_requests
+        .with_label_values(&[method, response.status().as_str()])
+        .inc();
+    METRICS
+        .http_request_duration
+        .with_label_values(&[method])
+        .observe(start.elapsed().as_secs_f64());
+    let (mut parts, body) = response.into_parts();
+    // keep the length of bodies not streamed, the wrapped body has no size hint
+    if let Some(length) = body.size_hint().exact()
+        && length != 0
+        && !parts.headers.contains_key(header::CONTENT_LENGTH)
+    {
+        parts.headers.insert(header::CONTENT_LENGTH, length.into());
+    }
+    let body = Body::from_stream(
+        body.into_data_stream()
+            .inspect_ok(|data| METRICS.response_bytes.inc_by(data.len() as u64)),
+    );
+    Response::from_parts(parts, body)
+}
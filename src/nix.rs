@@ -1,9 +1,10 @@
 pub mod sign;
 
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fmt, fs,
     path::PathBuf,
+    str::FromStr,
 };
 
 use nix_base32::to_nix_base32;
@@ -17,6 +18,18 @@ use self::sign::{NixKeyPair, NixSignatureList};
 
 static STORE_PATH_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new("^([a-z0-9]+)-(.*)$").unwrap());
 
+pub const NIX_CACHE_INFO_KEY: &str = "nix-cache-info";
+pub const NIX_CACHE_INFO_CONTENT_TYPE: &str = "text/x-nix-cache-info";
+pub const NAR_CONTENT_TYPE: &str = "application/x-nix-nar";
+pub const NARINFO_CONTENT_TYPE: &str = "text/x-nix-narinfo";
+
+#[derive(Debug, Clone)]
+pub struct NixCacheInfo {
+    pub store_dir: String,
+    pub want_mass_query: bool,
+    pub priority: u32,
+}
+
 #[derive(Debug, Clone)]
 pub struct NarInfo {
     pub store_path: String,
@@ -215,6 +228,60 @@ impl NixHash {
     }
 }
 
+impl FromStr for NarInfo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidNarInfo(s.to_owned());
+        let mut fields = HashMap::new();
+        let mut sigs = Vec::new();
+        for line in s.lines().filter(|l| !l.is_empty()) {
+            let (name, value) = line.split_once(": ").ok_or_else(invalid)?;
+            if name == "Sig" {
+                sigs.push(value.parse()?);
+            } else {
+                fields.insert(name, value);
+            }
+        }
+        let field = |name| fields.get(name).copied().ok_or_else(invalid);
+        let size = |name| field(name)?.parse::<usize>().map_err(|_| invalid());
+        Ok(NarInfo {
+            store_path: field("StorePath")?.to_owned(),
+            url: field("URL")?.to_owned(),
+            // the default of nix
+            compression: fields.get("Compression").unwrap_or(&"bzip2").to_string(),
+            file_hash: field("FileHash")?.parse()?,
+            file_size: size("FileSize")?,
+            nar_hash: field("NarHash")?.parse()?,
+            nar_size: size("NarSize")?,
+            references: field("References")?
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect(),
+            deriver: fields.get("Deriver").map(|d| d.to_string()),
+            sigs: NixSignatureList(sigs),
+            ca: fields.get("CA").map(|c| c.to_string()),
+        })
+    }
+}
+
+impl NarInfo {
+    /// Fingerprint covered by signatures, the store directory is the one of the store path
+    pub fn fingerprint(&self) -> Result<String, Error> {
+        let (store_dir, _name) = self
+            .store_path
+            .rsplit_once('/')
+            .ok_or_else(|| Error::InvalidStorePath(self.store_path.clone()))?;
+        Ok(nar_info_fingerprint(
+            store_dir,
+            &self.store_path,
+            &self.nar_hash,
+            self.nar_size,
+            &self.references,
+        ))
+    }
+}
+
 impl fmt::Display for NarInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "StorePath: {}", self.store_path)?;
@@ -258,8 +325,59 @@ pub fn nar_info_fingerprint(
     fingerprint
 }
 
+impl fmt::Display for NixCacheInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "StoreDir: {}", self.store_dir)?;
+        writeln!(
+            f,
+            "WantMassQuery: {}",
+            if self.want_mass_query { 1 } else { 0 }
+        )?;
+        writeln!(f, "Priority: {}", self.priority)?;
+        Ok(())
+    }
+}
+
+impl FromStr for NixHash {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (algorithm, base32) = s
+            .split_once(':')
+            .ok_or_else(|| Error::InvalidNarInfo(s.to_owned()))?;
+        Ok(NixHash {
+            algorithm: algorithm.to_owned(),
+            base32: base32.to_owned(),
+        })
+    }
+}
+
 impl fmt::Display for NixHash {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}:{}", self.algorithm, self.base32)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_nar_info() {
+        let text = "StorePath: /nix/store/0000000000000000000000000000000a-hello\n\
+                    URL: nar/0000000000000000000000000000000000000000000000000000.nar.zst\n\
+                    Compression: zstd\n\
+                    FileHash: sha256:0000000000000000000000000000000000000000000000000000\n\
+                    FileSize: 10\n\
+                    NarHash: sha256:1111111111111111111111111111111111111111111111111111\n\
+                    NarSize: 20\n\
+                    References: 0000000000000000000000000000000a-hello 0000000000000000000000000000000b-glibc\n\
+                    Sig: cache:c2lnbmF0dXJl\n";
+        let nar_info: NarInfo = text.parse().unwrap();
+        assert_eq!(nar_info.nar_size, 20);
+        assert_eq!(nar_info.references.len(), 2);
+        assert_eq!(nar_info.sigs.0.len(), 1);
+        assert_eq!(nar_info.to_string(), text);
+        assert!("StorePath: /nix/store/a".parse::<NarInfo>().is_err());
+    }
+}
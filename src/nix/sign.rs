@@ -1,7 +1,7 @@
 use std::{fmt, str::FromStr};
 
 use data_encoding::BASE64;
-use ed25519_compact::{KeyPair, SecretKey, Signature};
+use ed25519_compact::{KeyPair, PublicKey, SecretKey, Signature};
 use once_cell::sync::Lazy;
 use regex::Regex;
 
@@ -15,6 +15,12 @@ pub struct NixKeyPair {
     pub key_pair: KeyPair,
 }
 
+#[derive(Debug, Clone)]
+pub struct NixPublicKey {
+    pub name: String,
+    pub key: PublicKey,
+}
+
 #[derive(Debug, Clone)]
 pub struct NixSignature {
     pub name: String,
@@ -53,6 +59,33 @@ impl NixKeyPair {
     }
 }
 
+impl FromStr for NixPublicKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let c = SIG_REGEX
+            .captures(s)
+            .ok_or(Error::InvalidPublicKey(s.to_owned()))?;
+        let pk_bytes = BASE64.decode(c[2].as_bytes())?;
+        Ok(NixPublicKey {
+            name: c[1].to_owned(),
+            key: PublicKey::from_slice(&pk_bytes)?,
+        })
+    }
+}
+
+impl NixPublicKey {
+    pub fn verify(&self, data: &[u8], signature: &NixSignature) -> Result<(), Error> {
+        Ok(self.key.verify(data, &signature.signature()?)?)
+    }
+}
+
+impl fmt::Display for NixPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, BASE64.encode(self.key.as_ref()))
+    }
+}
+
 impl FromStr for NixSignature {
     type Err = Error;
 
@@ -97,6 +130,14 @@ impl NixSignatureList {
 }
 
 impl NixSignatureList {
+    /// Whether one of the signatures is a valid signature of `data` by one of `keys`
+    pub fn is_trusted(&self, keys: &[NixPublicKey], data: &[u8]) -> bool {
+        self.0.iter().any(|s| {
+            keys.iter()
+                .any(|k| k.name == s.name && k.verify(data, s).is_ok())
+        })
+    }
+
     pub fn merge(
         &mut self,
         key_pair: &NixKeyPair,
@@ -139,3 +180,30 @@ impl fmt::Display for NixSignatureList {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use ed25519_compact::Seed;
+
+    use super::*;
+
+    #[test]
+    fn test_is_trusted() {
+        let key_pair = NixKeyPair {
+            name: "test-1".to_owned(),
+            key_pair: KeyPair::from_seed(Seed::new([1; 32])),
+        };
+        let public_key: NixPublicKey =
+            format!("test-1:{}", BASE64.encode(key_pair.key_pair.pk.as_ref()))
+                .parse()
+                .unwrap();
+        let sigs = NixSignatureList(vec![key_pair.sign(b"data").unwrap()]);
+        assert!(sigs.is_trusted(std::slice::from_ref(&public_key), b"data"));
+        assert!(!sigs.is_trusted(std::slice::from_ref(&public_key), b"other"));
+        let renamed = NixPublicKey {
+            name: "test-2".to_owned(),
+            ..public_key
+        };
+        assert!(!sigs.is_trusted(&[renamed], b"data"));
+    }
+}
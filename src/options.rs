@@ -2,9 +2,13 @@ use clap::{Parser, Subcommand};
 use regex::Regex;
 use reqwest::Url;
 
+use std::fmt;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 use crate::convert::EncodingOptions;
+use crate::nix::sign::NixPublicKey;
 
 #[derive(Clone, Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -13,28 +17,105 @@ pub struct Options {
     pub command: Commands,
 }
 
+/// `--config` of subcommands reading the configuration file
+#[derive(Clone, Debug, Parser)]
+pub struct ConfigFileOptions {
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "TOML configuration file of `server` and `push` options, \
+                also read from `ORANC_CONFIG`"
+    )]
+    pub config: Option<PathBuf>,
+}
+
 #[derive(Clone, Debug, Subcommand)]
 pub enum Commands {
-    Server(ServerOptions),
+    Server(Box<ServerOptions>),
     #[command(subcommand)]
     Tag(TagCommands),
     Push(PushOptions),
     Completion(CompletionOptions),
+    #[command(subcommand)]
+    Config(ConfigCommands),
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum ConfigCommands {
+    #[command(about = "Validate a configuration file")]
+    Check {
+        #[arg(help = "configuration file, defaults to `--config` or `ORANC_CONFIG`")]
+        path: Option<PathBuf>,
+        #[clap(flatten)]
+        config_file_options: ConfigFileOptions,
+    },
 }
 
 #[derive(Clone, Debug, Parser)]
 #[command(about = "HTTP Nix cache server backed by OCI Registry")]
 pub struct ServerOptions {
-    #[arg(short, long, default_value = "[::]:8080")]
-    pub listen: SocketAddr,
+    #[arg(
+        short,
+        long,
+        value_name = "ADDRESS",
+        default_value = "[::]:8080",
+        help = "`HOST:PORT` or `unix:PATH` to listen on, \
+                ignored if a socket is passed by systemd socket activation"
+    )]
+    pub listen: Listen,
     #[arg(short, long, value_name = "NUM", default_value = "2")]
     pub repository_parts: usize,
     #[arg(short, long, value_name = "NUM", default_value = "3")]
     pub max_retry: usize,
     #[arg(long, help = "disable ssl")]
     pub no_ssl: bool,
-    #[arg(short, long, value_name = "URL", help = "upstream cache URLs")]
-    pub upstream: Vec<Url>,
+    #[arg(
+        short,
+        long,
+        value_name = "URL[,KEY=VALUE...]",
+        help = "upstream cache URLs with optional settings, \
+                e.g. `https://cache.nixos.org,mode=proxy,cache=true,priority=10,timeout=3`, \
+                `mode` is `redirect` (default) or `proxy`, \
+                `cache` stores proxied NARs in the blob cache, \
+                upstreams with lower `priority` (default 50) are preferred, \
+                `timeout` overrides `--upstream-timeout`"
+    )]
+    pub upstream: Vec<Upstream>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "5",
+        help = "timeout of querying an upstream"
+    )]
+    pub upstream_timeout: u64,
+    #[arg(
+        long,
+        value_name = "NUM",
+        default_value = "16384",
+        help = "number of cached keys missing in all upstreams, 0 to disable"
+    )]
+    pub upstream_miss_cache_size: usize,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "60",
+        help = "time to live of cached keys missing in all upstreams"
+    )]
+    pub upstream_miss_cache_ttl: u64,
+    #[arg(
+        long,
+        help = "copy narinfos and NARs served from upstreams into the repository in background, \
+                with credentials of authenticated clients"
+    )]
+    pub upstream_mirror: bool,
+    #[arg(
+        long,
+        value_name = "KEY",
+        help = "only use narinfos from upstreams signed by one of the public keys, \
+                e.g. `cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=`"
+    )]
+    pub upstream_trusted_public_key: Vec<NixPublicKey>,
     #[arg(
         short,
         long,
@@ -45,8 +126,320 @@ pub struct ServerOptions {
     pub ignore_upstream: Regex,
     #[arg(long, help = "upstream anonymous queries")]
     pub upstream_anonymous: bool,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "600",
+        help = "drop pooled registry clients unused for this long"
+    )]
+    pub client_idle_timeout: u64,
+    #[arg(
+        long,
+        value_name = "NUM",
+        default_value = "256",
+        help = "number of pooled registry clients, least recently used ones are dropped first"
+    )]
+    pub client_pool_size: usize,
+    #[arg(
+        long,
+        value_name = "NUM",
+        default_value = "4096",
+        help = "number of cached layer infos, 0 to disable"
+    )]
+    pub layer_cache_size: usize,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "60",
+        help = "time to live of cached layer infos"
+    )]
+    pub layer_cache_ttl: u64,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "10",
+        help = "time to live of cached missing keys"
+    )]
+    pub layer_cache_negative_ttl: u64,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "directory of the on-disk blob cache"
+    )]
+    pub blob_cache_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "10737418240",
+        help = "maximum total size of the on-disk blob cache"
+    )]
+    pub blob_cache_size: u64,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "16777216",
+        help = "bytes of a blob fetch buffered for concurrent requests of the blob, \
+                requests falling further behind fetch the rest themselves, 0 to disable coalescing"
+    )]
+    pub blob_flight_buffer: u64,
+    #[arg(
+        long,
+        help = "serve a synthesized nix-cache-info for repositories without one"
+    )]
+    pub synthesize_nix_cache_info: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = "/nix/store",
+        help = "store dir of synthesized nix-cache-info"
+    )]
+    pub cache_info_store_dir: String,
+    #[arg(
+        long,
+        value_name = "NUM",
+        default_value = "41",
+        help = "priority of synthesized nix-cache-info"
+    )]
+    pub cache_info_priority: u32,
+    #[arg(long, help = "disable mass query in synthesized nix-cache-info")]
+    pub cache_info_no_mass_query: bool,
+    #[arg(
+        long,
+        value_name = "REPOSITORY,KEY=VALUE...",
+        help = "per repository settings of synthesized nix-cache-info, \
+                e.g. `ghcr.io/owner/cache,priority=30,mass-query=false,store-dir=/nix/store`"
+    )]
+    pub repository_cache_info: Vec<RepositoryCacheInfo>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "verify S3 signatures against access keys listed in this file, \
+                one `ACCESS_KEY_ID SECRET_ACCESS_KEY [REGISTRY_USERNAME REGISTRY_PASSWORD]` per line"
+    )]
+    pub s3_credentials_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "registry credentials used for anonymous reads, \
+                whitespace separated `REGISTRY=USERNAME:PASSWORD` entries, \
+                also read from `ORANC_REGISTRY_CREDENTIALS`"
+    )]
+    pub registry_credentials_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "86400",
+        help = "drop unfinished S3 multipart uploads after this long"
+    )]
+    pub multipart_upload_timeout: u64,
+    #[arg(
+        long,
+        value_name = "NUM",
+        default_value = "1000",
+        help = "maximum number of unfinished S3 multipart uploads"
+    )]
+    pub multipart_max_uploads: usize,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "268435456",
+        help = "maximum total size of S3 multipart upload parts buffered before reaching the registry"
+    )]
+    pub multipart_max_buffer: usize,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "nix signing key added to signatures of served narinfos, \
+                also read from `ORANC_SERVER_SIGNING_KEY`"
+    )]
+    pub signing_key_file: Option<PathBuf>,
+    #[arg(long, help = "serve prometheus metrics at `/metrics`")]
+    pub metrics: bool,
+    #[arg(
+        long,
+        value_name = "REPOSITORY",
+        help = "repositories checked by `/readyz`, e.g. `ghcr.io/owner/cache`"
+    )]
+    pub ready_check: Vec<String>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "5",
+        help = "timeout of readiness checks"
+    )]
+    pub ready_check_timeout: u64,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "10",
+        help = "time to reuse the last readiness result"
+    )]
+    pub ready_check_cache_ttl: u64,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "30",
+        help = "time to drain in-flight requests on SIGTERM or SIGINT"
+    )]
+    pub shutdown_timeout: u64,
     #[clap(flatten)]
     pub encoding_options: EncodingOptions,
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
+}
+
+/// Address the server listens on
+#[derive(Clone, Debug)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("empty unix socket path".to_owned()),
+            Some(path) => Ok(Listen::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(Listen::Tcp)
+                .map_err(|e| format!("invalid socket address '{s}': {e}")),
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{addr}"),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Upstream cache and its settings
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    pub url: Url,
+    pub mode: UpstreamMode,
+    /// Store proxied NARs in the blob cache
+    pub cache: bool,
+    /// Lower values are preferred, ties are broken by the order of upstreams
+    pub priority: u32,
+    /// Overrides `--upstream-timeout`, in seconds
+    pub timeout: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamMode {
+    /// Redirect clients to the upstream
+    Redirect,
+    /// Fetch from the upstream and stream the response to clients
+    Proxy,
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let url = parts.next().unwrap_or_default();
+        let url = Url::parse(url).map_err(|e| format!("invalid url '{url}': {e}"))?;
+        let mut result = Upstream {
+            url,
+            mode: UpstreamMode::Redirect,
+            cache: false,
+            priority: 50,
+            timeout: None,
+        };
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("invalid setting '{part}', expecting KEY=VALUE"))?;
+            match key {
+                "mode" => {
+                    result.mode = match value {
+                        "redirect" => UpstreamMode::Redirect,
+                        "proxy" => UpstreamMode::Proxy,
+                        _ => return Err(format!("invalid mode '{value}'")),
+                    }
+                }
+                "cache" => {
+                    result.cache = value
+                        .parse()
+                        .map_err(|e| format!("invalid cache '{value}': {e}"))?
+                }
+                "priority" => {
+                    result.priority = value
+                        .parse()
+                        .map_err(|e| format!("invalid priority '{value}': {e}"))?
+                }
+                "timeout" => {
+                    result.timeout = Some(
+                        value
+                            .parse()
+                            .map_err(|e| format!("invalid timeout '{value}': {e}"))?,
+                    )
+                }
+                _ => return Err(format!("unknown setting '{key}'")),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Per repository overrides of synthesized nix-cache-info
+#[derive(Clone, Debug)]
+pub struct RepositoryCacheInfo {
+    /// `{registry}/{repository}`
+    pub repository: String,
+    pub store_dir: Option<String>,
+    pub want_mass_query: Option<bool>,
+    pub priority: Option<u32>,
+}
+
+impl FromStr for RepositoryCacheInfo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let repository = match parts.next() {
+            Some(r) if !r.is_empty() => r.to_owned(),
+            _ => return Err(format!("missing repository in '{s}'")),
+        };
+        let mut result = RepositoryCacheInfo {
+            repository,
+            store_dir: None,
+            want_mass_query: None,
+            priority: None,
+        };
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("invalid setting '{part}', expecting KEY=VALUE"))?;
+            match key {
+                "store-dir" => result.store_dir = Some(value.to_owned()),
+                "mass-query" => {
+                    result.want_mass_query = Some(
+                        value
+                            .parse()
+                            .map_err(|e| format!("invalid mass-query '{value}': {e}"))?,
+                    )
+                }
+                "priority" => {
+                    result.priority = Some(
+                        value
+                            .parse()
+                            .map_err(|e| format!("invalid priority '{value}': {e}"))?,
+                    )
+                }
+                _ => return Err(format!("unknown setting '{key}'")),
+            }
+        }
+        Ok(result)
+    }
 }
 
 #[derive(Clone, Debug, Subcommand)]
@@ -102,6 +495,8 @@ pub struct PushOptions {
     pub no_ssl: bool,
     #[clap(flatten)]
     pub encoding_options: EncodingOptions,
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
     #[command(subcommand)]
     pub subcommand: Option<PushSubcommands>,
 }
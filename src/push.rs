@@ -15,11 +15,12 @@ use tempfile::tempdir_in;
 
 const NIX_DB_DIR: &str = "/nix/var/nix/db";
 static NIX_DB_FILE: Lazy<String> = Lazy::new(|| format!("{}/db.sqlite", NIX_DB_DIR));
-const NAR_CONTENT_TYPE: &str = "application/x-nix-nar";
-const NARINFO_CONTENT_TYPE: &str = "text/x-nix-narinfo";
 
 use crate::nix::sign::{NixKeyPair, NixSignatureList};
-use crate::nix::{NarInfo, NixHash};
+use crate::nix::{
+    NAR_CONTENT_TYPE, NARINFO_CONTENT_TYPE, NIX_CACHE_INFO_CONTENT_TYPE, NIX_CACHE_INFO_KEY,
+    NarInfo, NixCacheInfo, NixHash,
+};
 use crate::registry::{OciItem, OciLocation, RegistryOptions};
 use crate::{
     error::Error,
@@ -77,20 +78,15 @@ fn get_nix_key_pair() -> Result<NixKeyPair, Error> {
     NixKeyPair::from_secret_key_str(&sk_str)
 }
 
-fn build_nix_cache_info(options: &PushOptions, initialize_options: &InitializeOptions) -> String {
-    format!(
-        "StoreDir: {store_dir}
-WantMassQuery: {mass_query}
-Priority: {priority}
-",
-        store_dir = options.store_dir,
-        mass_query = if initialize_options.no_mass_query {
-            0
-        } else {
-            1
-        },
-        priority = initialize_options.priority
-    )
+fn build_nix_cache_info(
+    options: &PushOptions,
+    initialize_options: &InitializeOptions,
+) -> NixCacheInfo {
+    NixCacheInfo {
+        store_dir: options.store_dir.clone(),
+        want_mass_query: !initialize_options.no_mass_query,
+        priority: initialize_options.priority,
+    }
 }
 
 async fn push_one(
@@ -321,10 +317,10 @@ pub async fn push_initialize_main(
     options: PushOptions,
     initialize_options: InitializeOptions,
 ) -> Result<(), Error> {
-    let nix_cache_info = build_nix_cache_info(&options, &initialize_options);
+    let nix_cache_info = build_nix_cache_info(&options, &initialize_options).to_string();
     log::debug!("nix-cache-info:\n{nix_cache_info}");
-    let key = "nix-cache-info".to_owned();
-    let content_type = "text/x-nix-cache-info".to_owned();
+    let key = NIX_CACHE_INFO_KEY.to_owned();
+    let content_type = NIX_CACHE_INFO_CONTENT_TYPE.to_owned();
     let mut ctx = RegistryOptions::from_push_options(&options).context(auth);
     let location = OciLocation {
         registry: options.registry,
@@ -1,34 +1,63 @@
 use std::collections::{HashMap, VecDeque};
 use std::fmt;
-use std::sync::Arc;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::convert::EncodingOptions;
+use crate::metrics::METRICS;
 use crate::server::ServerContext;
+use crate::server::access_log;
 use crate::{
     error::Error,
     options::{PushOptions, ServerOptions},
 };
 use axum::RequestPartsExt;
+use axum::body::Bytes;
 use axum::extract::{FromRef, FromRequestParts, Path, Query, State};
+use data_encoding::HEXLOWER;
+use futures::{Stream, StreamExt, stream::BoxStream};
+use http::{
+    StatusCode,
+    header::{ACCEPT, RANGE},
+};
 use maplit::btreemap;
 use oci_client::{
-    Client, Reference,
+    Client, Reference, RegistryOperation,
     client::{ClientConfig, ClientProtocol, Config, ImageLayer},
     config::{Architecture, ConfigFile, Os, Rootfs},
     errors::{OciDistributionError, OciErrorCode},
-    manifest::OciImageManifest,
+    manifest::{IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE, OciDescriptor, OciImageManifest},
     secrets::RegistryAuth,
 };
+use sha2::{Digest, Sha256};
+use upload::{BlobUpload, UploadedBlob};
+
+pub mod upload;
 
 pub const LAYER_MEDIA_TYPE: &str = "application/octet-stream";
 pub const CONTENT_TYPE_ANNOTATION: &str = "com.linyinfeng.oranc.content.type";
 
+/// Pull tokens are reused for a while, registries usually grant them for longer
+const PULL_TOKEN_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
 pub struct RegistryContext {
     pub options: RegistryOptions,
     pub client: Client,
+    /// For requests not covered by `oci_client`
+    pub http_client: reqwest::Client,
+    pub pull_tokens: PullTokens,
     pub auth: RegistryAuth,
 }
 
+/// Credentials for pulls sent with `http_client`, by repository
+///
+/// Manifests and blobs are pulled without `oci_client`,
+/// whose errors do not keep response headers like `Retry-After`.
+#[derive(Clone, Default)]
+pub struct PullTokens(Arc<Mutex<HashMap<String, (RegistryAuth, Instant)>>>);
+
 #[derive(Debug, Clone)]
 pub struct RegistryOptions {
     pub no_ssl: bool,
@@ -37,7 +66,7 @@ pub struct RegistryOptions {
     pub encoding_options: EncodingOptions,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct OciLocation {
     pub registry: String,
     pub repository: String,
@@ -54,6 +83,7 @@ pub struct OciItem {
 pub struct LayerInfo {
     pub reference: Reference,
     pub digest: String,
+    pub size: u64,
     pub content_type: String,
 }
 
@@ -84,23 +114,35 @@ where
         let (repository, key_path) = match params.get("repository") {
             Some(r) => (r.clone(), path_parts),
             None => {
-                if path_parts.len() <= ctx.options.repository_parts {
+                // S3 ListObjectsV2 requests address the repository itself, with an empty key
+                let list_request = params.contains_key("list-type");
+                if path_parts.len() < ctx.options.repository_parts
+                    || (path_parts.len() == ctx.options.repository_parts && !list_request)
+                {
                     return Err(path_err());
                 }
                 // `path_parts.len() > ctx.options.repository_parts``
                 // `path_parts.len() >= ctx.options.repository_parts + 1``
-                // so that we have `!remain.is_empty()`
+                // so that we have `!remain.is_empty()` unless for list requests
                 let remain = path_parts.split_off(ctx.options.repository_parts);
                 let repository = Vec::from(path_parts).join("/");
                 (repository, remain)
             }
         };
         let key = Vec::from(key_path).join("/");
-        Ok(OciLocation {
+        let location = OciLocation {
             registry,
             repository,
             key,
-        })
+        };
+        access_log::record(|entry| {
+            if !location.key.is_empty() {
+                let (reference, _fallbacks) = location.reference(&ctx.options.encoding_options);
+                entry.tag = reference.tag().map(str::to_owned);
+            }
+            entry.location = Some(location.clone());
+        });
+        Ok(location)
     }
 }
 
@@ -138,37 +180,43 @@ pub async fn get_layer_info(
         let mut ref_errors = vec![];
         'retries: for attempt in 1..max_retry {
             log::debug!("pull image manifest {reference:?}, attempt {attempt}/{max_retry}");
-            match ctx.client.pull_image_manifest(&reference, &ctx.auth).await {
-                Ok(res) => {
-                    pull_result = Some((reference.clone(), res));
+            let start = Instant::now();
+            let result = pull_manifest(ctx, &reference).await;
+            METRICS.observe_registry("manifest", start);
+            match result {
+                Ok(Some(manifest)) => {
+                    pull_result = Some((reference.clone(), manifest));
                     break 'fallbacks;
                 }
-                Err(OciDistributionError::ImageManifestNotFoundError(_)) => break 'retries,
-                Err(OciDistributionError::RegistryError { envelope, .. })
-                    if envelope
-                        .errors
-                        .iter()
-                        .all(|e| e.code == OciErrorCode::ManifestUnknown) =>
-                {
-                    break 'retries;
+                Ok(None) => break 'retries,
+                // retrying or trying fallbacks with the same credentials will not help
+                Err(e) if e.code() == StatusCode::UNAUTHORIZED => {
+                    log::info!("pull image manifest {reference:?} unauthorized: {e}");
+                    return Err(e);
                 }
-                Err(oci_error) => {
-                    let e = oci_error.into();
+                Err(e) => {
                     log::warn!(
                         "pull image manifest {reference:?}, attempt {attempt}/{max_retry} failed: {}",
                         e
                     );
+                    if attempt + 1 < max_retry {
+                        METRICS
+                            .registry_retries
+                            .with_label_values(&["manifest"])
+                            .inc();
+                    }
                     ref_errors.push(e);
                 }
             }
         }
-        if ref_errors.len() == max_retry {
+        // attempts made are one less than `max_retry`
+        if !ref_errors.is_empty() && ref_errors.len() == max_retry - 1 {
             log::error!("pull image manifest {reference:?} failed");
             // all reties failed
             errors.extend(ref_errors);
         }
     }
-    let (reference, (manifest, _hash)) = match pull_result {
+    let (reference, manifest) = match pull_result {
         Some(r) => r,
         None => {
             if errors.is_empty() {
@@ -203,9 +251,12 @@ pub async fn get_layer_info(
             ));
         }
     };
+    let size = u64::try_from(layer_manifest.size)
+        .map_err(|_| Error::InvalidLayerSize(layer_manifest.size))?;
     let info = LayerInfo {
         reference,
         digest: layer_manifest.digest.clone(),
+        size,
         content_type: content_type.clone(),
     };
     Ok(Some(info))
@@ -216,26 +267,108 @@ pub async fn put(
     location: &OciLocation,
     oci_item: OciItem,
 ) -> Result<(), Error> {
-    let content_type = match oci_item.content_type {
+    let layer = ImageLayer::new(oci_item.data, LAYER_MEDIA_TYPE.to_string(), None);
+    let descriptor = OciDescriptor {
+        size: layer.data.len() as i64,
+        digest: layer.sha256_digest(),
+        ..Default::default()
+    };
+    let (config, image_manifest) = build_image(location, oci_item.content_type, descriptor)?;
+    push_image(ctx, location, &[layer], config, image_manifest).await
+}
+
+/// Puts an item from a stream, the layer is uploaded in chunks as the stream is read,
+/// so that memory use does not depend on the size of the item
+pub async fn put_stream<S, E>(
+    ctx: &mut RegistryContext,
+    location: &OciLocation,
+    content_type: Option<String>,
+    stream: S,
+) -> Result<(), Error>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    Error: From<E>,
+{
+    if ctx.options.dry_run {
+        log::debug!("dry run, skipped");
+        return Ok(());
+    }
+    let blob = upload_stream(ctx, location, stream).await?;
+    put_uploaded(ctx, location, content_type, blob).await
+}
+
+/// Uploads a blob to the repository of `location` without tagging it
+pub async fn upload_stream<S, E>(
+    ctx: &RegistryContext,
+    location: &OciLocation,
+    stream: S,
+) -> Result<UploadedBlob, Error>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    Error: From<E>,
+{
+    let (reference, _fallbacks) = location.reference(&ctx.options.encoding_options);
+    let mut upload = BlobUpload::begin(ctx, &reference).await?;
+    let mut stream = pin!(stream);
+    while let Some(data) = stream.next().await {
+        upload.write(data?).await?;
+    }
+    upload.finish().await
+}
+
+/// Uploads the concatenation of `parts`, which are blobs in the repository of `location`
+pub async fn upload_concat(
+    ctx: &RegistryContext,
+    location: &OciLocation,
+    parts: &[UploadedBlob],
+) -> Result<UploadedBlob, Error> {
+    let (reference, _fallbacks) = location.reference(&ctx.options.encoding_options);
+    let mut upload = BlobUpload::begin(ctx, &reference).await?;
+    for part in parts {
+        let mut stream = pull_blob_stream(ctx, &reference, &part.digest).await?;
+        while let Some(data) = stream.next().await {
+            upload.write(data?).await?;
+        }
+    }
+    upload.finish().await
+}
+
+/// Tags an uploaded blob as the item of `location`
+pub async fn put_uploaded(
+    ctx: &mut RegistryContext,
+    location: &OciLocation,
+    content_type: Option<String>,
+    blob: UploadedBlob,
+) -> Result<(), Error> {
+    let descriptor = OciDescriptor {
+        size: blob.size as i64,
+        digest: blob.digest,
+        ..Default::default()
+    };
+    let (config, image_manifest) = build_image(location, content_type, descriptor)?;
+    // the layer is already in the registry, only push config and manifest
+    push_image(ctx, location, &[], config, image_manifest).await
+}
+
+/// Builds config and manifest of the single layer image for `location`
+fn build_image(
+    location: &OciLocation,
+    content_type: Option<String>,
+    layer: OciDescriptor,
+) -> Result<(Config, OciImageManifest), Error> {
+    let content_type = match content_type {
         None => "application/octet-stream".to_string(),
         Some(c) => c,
     };
     let layer_annotations = btreemap! {
         CONTENT_TYPE_ANNOTATION.to_string() => content_type,
     };
-    let layer = ImageLayer::new(
-        oci_item.data,
-        LAYER_MEDIA_TYPE.to_string(),
-        Some(layer_annotations),
-    );
-    let layer_digest = layer.sha256_digest();
-    let layers = vec![layer];
 
     let rootfs = Rootfs {
         r#type: "layers".to_string(),
         diff_ids: vec![
             // just use layer digest
-            layer_digest,
+            layer.digest.clone(),
         ],
     };
     let config_file = ConfigFile {
@@ -257,8 +390,23 @@ pub async fn put(
         "com.linyinfeng.oranc.key".to_string() => key.to_owned(),
         "org.opencontainers.image.description".to_string() => key.to_owned(),
     };
-    let image_manifest = OciImageManifest::build(&layers, &config, Some(image_annotations));
+    let mut image_manifest = OciImageManifest::build(&[], &config, Some(image_annotations));
+    image_manifest.layers.push(OciDescriptor {
+        media_type: LAYER_MEDIA_TYPE.to_string(),
+        annotations: Some(layer_annotations),
+        ..layer
+    });
+    Ok((config, image_manifest))
+}
 
+/// Pushes `layers` (may be already pushed and omitted), config and manifest
+async fn push_image(
+    ctx: &mut RegistryContext,
+    location: &OciLocation,
+    layers: &[ImageLayer],
+    config: Config,
+    image_manifest: OciImageManifest,
+) -> Result<(), Error> {
     let max_retry = ctx.options.max_retry;
     if max_retry < 1 {
         return Err(Error::InvalidMaxRetry(max_retry));
@@ -275,7 +423,7 @@ pub async fn put(
             .client
             .push(
                 &reference,
-                &layers,
+                layers,
                 config.clone(),
                 &ctx.auth,
                 Some(image_manifest.clone()),
@@ -289,6 +437,9 @@ pub async fn put(
                     "push {reference:?}, attempt {attempt}/{max_retry} failed: {}",
                     e
                 );
+                if attempt + 1 < max_retry {
+                    METRICS.registry_retries.with_label_values(&["push"]).inc();
+                }
                 errors.push(e);
             }
         }
@@ -296,6 +447,256 @@ pub async fn put(
     Err(Error::RetryAllFails(errors))
 }
 
+/// Credentials for requests sent without `oci_client`
+pub async fn request_auth(
+    ctx: &RegistryContext,
+    reference: &Reference,
+    operation: RegistryOperation,
+) -> Result<RegistryAuth, Error> {
+    match ctx.client.auth(reference, &ctx.auth, operation).await? {
+        Some(token) => Ok(RegistryAuth::Bearer(token)),
+        // the registry does not use token authentication
+        None => Ok(ctx.auth.clone()),
+    }
+}
+
+pub fn authenticate(
+    request: reqwest::RequestBuilder,
+    auth: &RegistryAuth,
+) -> reqwest::RequestBuilder {
+    match auth {
+        RegistryAuth::Anonymous => request,
+        RegistryAuth::Basic(username, password) => request.basic_auth(username, Some(password)),
+        RegistryAuth::Bearer(token) => request.bearer_auth(token),
+    }
+}
+
+/// URL of `path` under the repository of `reference` in the distribution API
+pub fn repository_url(ctx: &RegistryContext, reference: &Reference, path: &str) -> String {
+    let scheme = if ctx.options.no_ssl { "http" } else { "https" };
+    format!(
+        "{scheme}://{}/v2/{}/{path}",
+        reference.resolve_registry(),
+        reference.repository()
+    )
+}
+
+/// Error from an unexpected registry response, keeps `Retry-After` for clients
+pub async fn response_error(response: reqwest::Response) -> Error {
+    let status = response.status();
+    let retry_after = response.headers().get(http::header::RETRY_AFTER).cloned();
+    let message = response.text().await.unwrap_or_default();
+    Error::RegistryResponse {
+        status,
+        retry_after,
+        message,
+    }
+}
+
+/// Sends a pull request to the repository of `reference`, with cached credentials if any
+async fn send_pull<F>(
+    ctx: &RegistryContext,
+    reference: &Reference,
+    request: F,
+) -> Result<reqwest::Response, Error>
+where
+    F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+{
+    let key = format!(
+        "{}/{}",
+        reference.resolve_registry(),
+        reference.repository()
+    );
+    if let Some(auth) = ctx.pull_tokens.get(&key) {
+        let response = authenticate(request(&ctx.http_client), &auth)
+            .send()
+            .await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        log::debug!("cached pull credentials rejected: {key}");
+    }
+    let auth = request_auth(ctx, reference, RegistryOperation::Pull).await?;
+    ctx.pull_tokens.insert(key, auth.clone());
+    Ok(authenticate(request(&ctx.http_client), &auth)
+        .send()
+        .await?)
+}
+
+/// Pulls the image manifest of `reference`, `None` if it does not exist
+pub async fn pull_manifest(
+    ctx: &RegistryContext,
+    reference: &Reference,
+) -> Result<Option<OciImageManifest>, Error> {
+    let target = reference.digest().or(reference.tag()).unwrap_or("latest");
+    let url = repository_url(ctx, reference, &format!("manifests/{target}"));
+    let accept = format!("{OCI_IMAGE_MEDIA_TYPE}, {IMAGE_MANIFEST_MEDIA_TYPE}");
+    let response = send_pull(ctx, reference, |client| {
+        client.get(&url).header(ACCEPT, &accept)
+    })
+    .await?;
+    match response.status() {
+        StatusCode::OK => {
+            let body = response.bytes().await?;
+            let manifest = serde_json::from_slice(&body)
+                .map_err(|e| OciDistributionError::ManifestParsingError(e.to_string()))?;
+            Ok(Some(manifest))
+        }
+        StatusCode::NOT_FOUND => Ok(None),
+        _ => Err(response_error(response).await),
+    }
+}
+
+/// Pulls a blob, failing at the end if it does not match `digest`
+pub async fn pull_blob_stream(
+    ctx: &RegistryContext,
+    reference: &Reference,
+    digest: &str,
+) -> Result<BoxStream<'static, Result<Bytes, Error>>, Error> {
+    let response = pull_blob_partial(ctx, reference, digest, 0, None).await?;
+    Ok(verified(response.bytes_stream(), digest.to_owned()).boxed())
+}
+
+/// Pulls a blob from `offset`, the registry may ignore the range and respond `OK`
+pub async fn pull_blob_partial(
+    ctx: &RegistryContext,
+    reference: &Reference,
+    digest: &str,
+    offset: u64,
+    length: Option<u64>,
+) -> Result<reqwest::Response, Error> {
+    let url = repository_url(ctx, reference, &format!("blobs/{digest}"));
+    let range = match (offset, length) {
+        (0, None) => None,
+        (offset, None) => Some(format!("bytes={offset}-")),
+        (offset, Some(length)) => Some(format!(
+            "bytes={offset}-{}",
+            (offset + length).saturating_sub(1)
+        )),
+    };
+    let response = send_pull(ctx, reference, |client| match &range {
+        Some(range) => client.get(&url).header(RANGE, range),
+        None => client.get(&url),
+    })
+    .await?;
+    match response.status() {
+        StatusCode::OK | StatusCode::PARTIAL_CONTENT => Ok(response),
+        _ => Err(response_error(response).await),
+    }
+}
+
+/// Passes a blob through, failing instead of ending if it does not match a sha256 `digest`
+fn verified<S>(stream: S, digest: String) -> impl Stream<Item = Result<Bytes, Error>>
+where
+    S: Stream<Item = Result<Bytes, reqwest::Error>> + Unpin,
+{
+    futures::stream::unfold(
+        (stream, Some(Sha256::new())),
+        move |(mut stream, hasher)| {
+            let digest = digest.clone();
+            async move {
+                let mut hasher = hasher?;
+                match stream.next().await {
+                    Some(Ok(data)) => {
+                        hasher.update(&data);
+                        Some((Ok(data), (stream, Some(hasher))))
+                    }
+                    Some(Err(e)) => Some((Err(e.into()), (stream, None))),
+                    // other algorithms are not verified
+                    None if !digest.starts_with("sha256:") => None,
+                    None => {
+                        let actual = format!("sha256:{}", HEXLOWER.encode(&hasher.finalize()));
+                        if actual == digest {
+                            return None;
+                        }
+                        let e = Error::BlobDigestMismatch {
+                            expected: digest,
+                            actual,
+                        };
+                        Some((Err(e), (stream, None)))
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Lists at most `n` tags of the repository of `location` after `last`
+pub async fn list_tags(
+    ctx: &RegistryContext,
+    location: &OciLocation,
+    n: usize,
+    last: Option<&str>,
+) -> Result<Vec<String>, Error> {
+    let (reference, _fallbacks) = location.reference(&ctx.options.encoding_options);
+    let response = ctx
+        .client
+        .list_tags(&reference, &ctx.auth, Some(n), last)
+        .await?;
+    Ok(response.tags)
+}
+
+/// Deletes manifests of all references of `location`, returns whether anything is deleted
+pub async fn delete(ctx: &RegistryContext, location: &OciLocation) -> Result<bool, Error> {
+    let mut deleted = false;
+    for reference in location.references_merged(&ctx.options.encoding_options) {
+        if ctx.options.dry_run {
+            log::debug!("dry run, skipped deleting {reference:?}");
+            continue;
+        }
+        // the distribution spec only requires deleting manifests by digest
+        let digest = match ctx
+            .client
+            .fetch_manifest_digest(&reference, &ctx.auth)
+            .await
+        {
+            Ok(digest) => digest,
+            Err(e) if is_not_found(&e) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        log::debug!("delete {reference:?}: {digest}");
+        let auth = request_auth(ctx, &reference, RegistryOperation::Push).await?;
+        let url = repository_url(ctx, &reference, &format!("manifests/{digest}"));
+        let response = authenticate(ctx.http_client.delete(url), &auth)
+            .send()
+            .await?;
+        match response.status() {
+            http::StatusCode::ACCEPTED | http::StatusCode::OK => deleted = true,
+            http::StatusCode::NOT_FOUND => (),
+            _ => return Err(response_error(response).await),
+        }
+    }
+    Ok(deleted)
+}
+
+pub fn is_not_found(e: &OciDistributionError) -> bool {
+    match e {
+        OciDistributionError::ImageManifestNotFoundError(_) => true,
+        OciDistributionError::ServerError { code, .. } => *code == 404,
+        OciDistributionError::RegistryError { envelope, .. } => envelope.errors.iter().all(|e| {
+            matches!(
+                e.code,
+                OciErrorCode::ManifestUnknown | OciErrorCode::NotFound
+            )
+        }),
+        _ => false,
+    }
+}
+
+/// Whether the registry rejected the credentials, or requires credentials
+pub fn is_unauthorized(e: &OciDistributionError) -> bool {
+    match e {
+        OciDistributionError::UnauthorizedError { .. } => true,
+        OciDistributionError::AuthenticationFailure(_) => true,
+        OciDistributionError::ServerError { code, .. } => *code == 401,
+        OciDistributionError::RegistryError { envelope, .. } => envelope
+            .errors
+            .iter()
+            .any(|e| e.code == OciErrorCode::Unauthorized),
+        _ => false,
+    }
+}
+
 impl RegistryOptions {
     pub fn from_push_options(options: &PushOptions) -> Self {
         Self {
@@ -333,14 +734,42 @@ impl RegistryOptions {
 
     pub fn context(self, auth: RegistryAuth) -> RegistryContext {
         let client = self.client();
+        self.context_with_client(client, reqwest::Client::new(), PullTokens::default(), auth)
+    }
+
+    pub fn context_with_client(
+        self,
+        client: Client,
+        http_client: reqwest::Client,
+        pull_tokens: PullTokens,
+        auth: RegistryAuth,
+    ) -> RegistryContext {
         RegistryContext {
             options: self,
             client,
+            http_client,
+            pull_tokens,
             auth,
         }
     }
 }
 
+impl PullTokens {
+    fn get(&self, key: &str) -> Option<RegistryAuth> {
+        let tokens = self.0.lock().unwrap();
+        tokens
+            .get(key)
+            .filter(|(_, fetched)| fetched.elapsed() < PULL_TOKEN_TTL)
+            .map(|(auth, _)| auth.clone())
+    }
+
+    fn insert(&self, key: String, auth: RegistryAuth) {
+        let mut tokens = self.0.lock().unwrap();
+        tokens.retain(|_, (_, fetched)| fetched.elapsed() < PULL_TOKEN_TTL);
+        tokens.insert(key, (auth, Instant::now()));
+    }
+}
+
 impl fmt::Display for OciLocation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -0,0 +1,229 @@
+use bytes::{Bytes, BytesMut};
+use data_encoding::HEXLOWER;
+use http::{HeaderMap, HeaderValue, Method, StatusCode, header};
+use oci_client::{Reference, RegistryOperation, secrets::RegistryAuth};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use tokio::runtime::Handle;
+
+use crate::error::Error;
+
+use super::{RegistryContext, authenticate, repository_url, request_auth, response_error};
+
+/// Size of chunks sent to the registry, also the bound of data buffered by an upload
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Chunked blob upload session
+///
+/// `oci_client` needs the digest of a blob before uploading it,
+/// here the digest is computed while data is sent to the registry.
+/// Sessions dropped before `finish`, on errors or when clients go away, are cancelled in the background.
+pub struct BlobUpload {
+    ctx: RegistryContext,
+    reference: Reference,
+    auth: RegistryAuth,
+    location: Url,
+    buffer: BytesMut,
+    size: u64,
+    hasher: Sha256,
+    /// Whether the session exists in the registry and is not finished
+    open: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadedBlob {
+    pub digest: String,
+    pub size: u64,
+}
+
+impl BlobUpload {
+    pub async fn begin(ctx: &RegistryContext, reference: &Reference) -> Result<Self, Error> {
+        let auth = request_auth(ctx, reference, RegistryOperation::Push).await?;
+        let url = repository_url(ctx, reference, "blobs/uploads/");
+        let url = Url::parse(&url).map_err(|e| Error::InvalidUploadLocation(e.to_string()))?;
+        let mut upload = Self {
+            ctx: ctx.clone(),
+            reference: reference.clone(),
+            auth,
+            location: url.clone(),
+            buffer: BytesMut::new(),
+            size: 0,
+            hasher: Sha256::new(),
+            open: false,
+        };
+        let response = upload
+            .send(
+                Method::POST,
+                url,
+                HeaderMap::new(),
+                Bytes::new(),
+                StatusCode::ACCEPTED,
+                true,
+            )
+            .await?;
+        upload.location = next_location(&upload.location, &response)?;
+        upload.open = true;
+        log::debug!("begin blob upload {reference:?}: {}", upload.location);
+        Ok(upload)
+    }
+
+    pub async fn write(&mut self, data: Bytes) -> Result<(), Error> {
+        self.hasher.update(&data);
+        self.buffer.extend_from_slice(&data);
+        while self.buffer.len() >= CHUNK_SIZE {
+            let chunk = self.buffer.split_to(CHUNK_SIZE).freeze();
+            self.push_chunk(chunk).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> Result<UploadedBlob, Error> {
+        if !self.buffer.is_empty() {
+            let chunk = self.buffer.split().freeze();
+            self.push_chunk(chunk).await?;
+        }
+        let digest = format!(
+            "sha256:{}",
+            HEXLOWER.encode(&self.hasher.clone().finalize())
+        );
+        let mut url = self.location.clone();
+        url.query_pairs_mut().append_pair("digest", &digest);
+        self.send(
+            Method::PUT,
+            url,
+            HeaderMap::new(),
+            Bytes::new(),
+            StatusCode::CREATED,
+            true,
+        )
+        .await?;
+        self.open = false;
+        log::debug!(
+            "finish blob upload {:?}: {digest}, {} bytes",
+            self.reference,
+            self.size
+        );
+        Ok(UploadedBlob {
+            digest,
+            size: self.size,
+        })
+    }
+
+    async fn push_chunk(&mut self, chunk: Bytes) -> Result<(), Error> {
+        let start = self.size;
+        let end = start + chunk.len() as u64 - 1;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&format!("{start}-{end}")).expect("valid header value"),
+        );
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        // the registry may have stored part of a failed chunk, so it is not sent again
+        let response = self
+            .send(
+                Method::PATCH,
+                self.location.clone(),
+                headers,
+                chunk,
+                StatusCode::ACCEPTED,
+                false,
+            )
+            .await?;
+        self.location = next_location(&self.location, &response)?;
+        self.size = end + 1;
+        Ok(())
+    }
+
+    /// Sends a request of the session, failures other than expired tokens are only retried if `retry_failures`
+    async fn send(
+        &mut self,
+        method: Method,
+        url: Url,
+        headers: HeaderMap,
+        body: Bytes,
+        expected: StatusCode,
+        retry_failures: bool,
+    ) -> Result<reqwest::Response, Error> {
+        let max_retry = self.ctx.options.max_retry;
+        if max_retry < 1 {
+            return Err(Error::InvalidMaxRetry(max_retry));
+        }
+        let mut errors = vec![];
+        for attempt in 1..max_retry {
+            let request = self
+                .ctx
+                .http_client
+                .request(method.clone(), url.clone())
+                .headers(headers.clone())
+                .header(header::CONTENT_LENGTH, body.len())
+                .body(body.clone());
+            let e = match authenticate(request, &self.auth).send().await {
+                Ok(response) if response.status() == expected => return Ok(response),
+                Ok(response) if response.status() == StatusCode::UNAUTHORIZED => {
+                    // the token may expire during long uploads
+                    let e = response_error(response).await;
+                    self.auth =
+                        request_auth(&self.ctx, &self.reference, RegistryOperation::Push).await?;
+                    e
+                }
+                Ok(response)
+                    if retry_failures
+                        && (response.status().is_server_error()
+                            || response.status() == StatusCode::TOO_MANY_REQUESTS) =>
+                {
+                    response_error(response).await
+                }
+                Ok(response) => return Err(response_error(response).await),
+                Err(e) if retry_failures => e.into(),
+                Err(e) => return Err(e.into()),
+            };
+            log::warn!("{method} {url}, attempt {attempt}/{max_retry} failed: {e}");
+            errors.push(e);
+        }
+        Err(Error::RetryAllFails(errors))
+    }
+}
+
+impl Drop for BlobUpload {
+    fn drop(&mut self) {
+        if !self.open {
+            return;
+        }
+        let Ok(runtime) = Handle::try_current() else {
+            return;
+        };
+        let request = authenticate(
+            self.ctx.http_client.delete(self.location.clone()),
+            &self.auth,
+        );
+        let reference = self.reference.clone();
+        runtime.spawn(async move {
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    log::debug!("cancelled blob upload {reference:?}")
+                }
+                Ok(response) => log::debug!(
+                    "failed to cancel blob upload {reference:?}: {}",
+                    response.status()
+                ),
+                Err(e) => log::debug!("failed to cancel blob upload {reference:?}: {e}"),
+            }
+        });
+    }
+}
+
+fn next_location(current: &Url, response: &reqwest::Response) -> Result<Url, Error> {
+    let location = response
+        .headers()
+        .get(header::LOCATION)
+        .ok_or_else(|| Error::InvalidUploadLocation("no location header".to_string()))?
+        .to_str()
+        .map_err(|e| Error::InvalidUploadLocation(e.to_string()))?;
+    // location may be relative
+    current
+        .join(location)
+        .map_err(|e| Error::InvalidUploadLocation(e.to_string()))
+}
@@ -1,111 +1,466 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::error::Error;
+use crate::metrics;
+use crate::metrics::METRICS;
+use crate::nix::NIX_CACHE_INFO_CONTENT_TYPE;
+use crate::nix::sign::NixKeyPair;
 use crate::registry;
 use crate::registry::LayerInfo;
-use crate::registry::OciItem;
 use crate::registry::OciLocation;
+use crate::registry::RegistryContext;
 use crate::registry::RegistryOptions;
 use crate::registry::get_layer_info;
 use crate::server::auth::Auth;
+use crate::server::blob_cache::BlobCache;
+use crate::server::blob_flights::{BlobFlights, Fetch};
+use crate::server::cache::{LayerInfoCache, UpstreamMissCache};
+use crate::server::conditional::Precondition;
+use crate::server::credentials::RegistryCredentials;
+use crate::server::health::Readiness;
+use crate::server::listener::Listener;
+use crate::server::mirror::Mirrors;
+use crate::server::multipart::MultipartUploads;
+use crate::server::pool::ClientPool;
+use crate::server::range::ByteRange;
+use crate::server::range::RangeRequest;
+use crate::server::shutdown::Shutdown;
+use crate::server::sigv4::S3Credentials;
+use crate::server::single_flight::SingleFlight;
 
+pub mod access_log;
 pub mod auth;
+pub mod blob_cache;
+pub mod blob_flights;
+pub mod cache;
+pub mod cache_info;
+pub mod conditional;
+pub mod credentials;
+pub mod health;
+pub mod list;
+pub mod listener;
+pub mod mirror;
+pub mod multipart;
+pub mod pool;
+pub mod range;
+pub mod resign;
+pub mod s3;
+pub mod shutdown;
+pub mod sigv4;
+pub mod single_flight;
 pub mod upstream;
 
 use axum::Router;
 use axum::body::Body;
-use axum::body::Bytes;
+use axum::extract::Query;
 use axum::extract::State;
+use axum::middleware;
 use axum::response::Response;
+use axum::routing::delete;
 use axum::routing::get;
 use axum::routing::head;
+use axum::routing::post;
 use axum::routing::put;
 use axum_extra::TypedHeader;
 use axum_extra::headers::ContentType;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use http::HeaderMap;
 use http::StatusCode;
 use http::header;
+use oci_client::secrets::RegistryAuth;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeekExt;
+use tokio_util::io::ReaderStream;
 
 use crate::options::ServerOptions;
 
 const OK_RESPONSE_BODY: &str = "<_/>";
+/// Limit of buffered POST bodies, e.g. part lists of multipart uploads
+const MAX_POST_BODY_SIZE: usize = 2 * 1024 * 1024;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct ServerContext {
     pub options: ServerOptions,
     pub http_client: reqwest::Client,
+    pub clients: ClientPool,
+    pub layer_infos: LayerInfoCache,
+    pub upstream_misses: UpstreamMissCache,
+    pub blobs: Option<Arc<BlobCache>>,
+    pub blob_flights: BlobFlights,
+    pub s3_credentials: S3Credentials,
+    pub registry_credentials: RegistryCredentials,
+    pub multipart_uploads: MultipartUploads,
+    pub mirrors: Mirrors,
+    pub signing_key: Option<NixKeyPair>,
+    pub readiness: Readiness,
+    pub shutdown: Shutdown,
+    pub layer_info_calls:
+        SingleFlight<(OciLocation, String), Result<Option<LayerInfo>, Arc<Error>>>,
+}
+
+impl ServerContext {
+    /// Registry context for reading, anonymous clients read with server credentials if configured
+    pub fn read_context(&self, registry: &str, auth: RegistryAuth) -> RegistryContext {
+        let auth = match (auth, self.registry_credentials.get(registry)) {
+            (RegistryAuth::Anonymous, Some(server_auth)) => server_auth.clone(),
+            (auth, _) => auth,
+        };
+        self.registry_context(registry, auth)
+    }
+
+    /// Registry context for writing, server credentials are only for reading
+    pub fn write_context(
+        &self,
+        location: &OciLocation,
+        auth: RegistryAuth,
+    ) -> Result<RegistryContext, Error> {
+        if let RegistryAuth::Anonymous = auth
+            && self.registry_credentials.contains(&location.registry)
+        {
+            return Err(Error::AnonymousWrite(location.clone()));
+        }
+        Ok(self.registry_context(&location.registry, auth))
+    }
+
+    pub fn registry_context(&self, registry: &str, auth: RegistryAuth) -> RegistryContext {
+        let (client, pull_tokens) = self.clients.get(registry, &auth);
+        RegistryOptions::from_server_options(&self.options).context_with_client(
+            client,
+            self.http_client.clone(),
+            pull_tokens,
+            auth,
+        )
+    }
+
+    /// Signing key for re-signing the narinfo at `location`, if enabled
+    pub fn resigning_key(&self, location: &OciLocation) -> Option<&NixKeyPair> {
+        self.signing_key
+            .as_ref()
+            .filter(|_| location.key.ends_with(".narinfo"))
+    }
+
+    pub async fn layer_info(
+        &self,
+        registry_ctx: &RegistryContext,
+        location: &OciLocation,
+    ) -> Result<Option<LayerInfo>, Error> {
+        let fingerprint = auth::fingerprint(&registry_ctx.auth);
+        if let Some(info) = self.layer_infos.get(location, &fingerprint) {
+            log::debug!("layer info cache hit: {location}");
+            if let Some(tag) = info.as_ref().and_then(|i| i.reference.tag()) {
+                access_log::record(|entry| entry.tag = Some(tag.to_owned()));
+            }
+            return Ok(info);
+        }
+        // concurrent lookups of the same key share one registry call
+        let key = (location.clone(), fingerprint.clone());
+        let mut call_ctx = registry_ctx.clone();
+        let call_location = location.clone();
+        let info = self
+            .layer_info_calls
+            .run(key, || async move {
+                get_layer_info(&mut call_ctx, &call_location)
+                    .await
+                    .map_err(Arc::new)
+            })
+            .await
+            .map_err(|e| Arc::try_unwrap(e).unwrap_or_else(Error::Shared))?;
+        if let Some(tag) = info.as_ref().and_then(|i| i.reference.tag()) {
+            access_log::record(|entry| entry.tag = Some(tag.to_owned()));
+        }
+        self.layer_infos
+            .insert(location.clone(), fingerprint, info.clone());
+        Ok(info)
+    }
 }
 
 pub async fn server_main(options: ServerOptions) -> Result<(), Error> {
     let http_client = reqwest::Client::new();
+    let clients = ClientPool::new(
+        RegistryOptions::from_server_options(&options),
+        Duration::from_secs(options.client_idle_timeout),
+        options.client_pool_size,
+    );
+    let layer_infos = LayerInfoCache::new(
+        options.layer_cache_size,
+        Duration::from_secs(options.layer_cache_ttl),
+        Duration::from_secs(options.layer_cache_negative_ttl),
+    );
+    let upstream_misses = UpstreamMissCache::new(
+        options.upstream_miss_cache_size,
+        Duration::from_secs(options.upstream_miss_cache_ttl),
+    );
+    let blobs = match &options.blob_cache_dir {
+        Some(dir) => Some(Arc::new(BlobCache::open(dir, options.blob_cache_size)?)),
+        None => None,
+    };
+    let s3_credentials = match &options.s3_credentials_file {
+        Some(path) => sigv4::load_credentials(path)?,
+        None => Default::default(),
+    };
+    let registry_credentials =
+        RegistryCredentials::load(options.registry_credentials_file.as_deref())?;
+    let signing_key = resign::load_key(options.signing_key_file.as_deref())?;
+    let multipart_uploads = MultipartUploads::new(
+        Duration::from_secs(options.multipart_upload_timeout),
+        options.multipart_max_uploads,
+        options.multipart_max_buffer,
+    );
+    let blob_flights = BlobFlights::new(options.blob_flight_buffer);
     let ctx = Arc::new(ServerContext {
         options,
         http_client,
+        clients,
+        layer_infos,
+        upstream_misses,
+        blobs,
+        blob_flights,
+        s3_credentials,
+        registry_credentials,
+        multipart_uploads,
+        mirrors: Mirrors::default(),
+        signing_key,
+        readiness: Readiness::default(),
+        shutdown: Shutdown::listen()?,
+        layer_info_calls: SingleFlight::new(),
     });
+    tokio::spawn(multipart::purge_expired(Arc::downgrade(&ctx)));
 
     let app = Router::new()
         .route("/", get(async || "oranc: OCI Registry As Nix Cache"))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
         .route("/{*path}", get(get_key))
         .route("/{*path}", head(head_key))
         .route("/{*path}", put(put_key))
-        .with_state(ctx.clone());
+        .route("/{*path}", post(post_key))
+        .route("/{*path}", delete(delete_key))
+        .layer(middleware::from_fn(sigv4::verify_payload));
+    let app = if ctx.options.metrics {
+        app.route("/metrics", get(metrics::serve))
+            .layer(middleware::from_fn(metrics::track))
+    } else {
+        app
+    };
+    // applied last to log all routes
+    let app = app.layer(middleware::from_fn(access_log::layer));
+    let app = app.with_state(ctx.clone());
 
-    let listener = tokio::net::TcpListener::bind(&ctx.options.listen)
-        .await
-        .unwrap();
-    log::info!("listening on {:?}", ctx.options.listen);
-    Ok(axum::serve(listener, app).await?)
+    let shutdown = ctx.shutdown.clone();
+    let deadline = Duration::from_secs(ctx.options.shutdown_timeout);
+    match listener::bind(&ctx.options.listen).await? {
+        Listener::Tcp(listener) => {
+            let serve =
+                axum::serve(listener, app).with_graceful_shutdown(shutdown.clone().triggered());
+            shutdown::serve_until(serve, &ctx, shutdown, deadline).await
+        }
+        Listener::Unix(listener) => {
+            let serve =
+                axum::serve(listener, app).with_graceful_shutdown(shutdown.clone().triggered());
+            shutdown::serve_until(serve, &ctx, shutdown, deadline).await
+        }
+    }
 }
 
 async fn get_key(
     State(ctx): State<Arc<ServerContext>>,
     location: OciLocation,
     Auth(auth): Auth,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
 ) -> Result<Response<Body>, Error> {
-    log::info!("get: {location}");
-    if let Some(response) = upstream::check_and_redirect(&ctx, &location.key, &auth).await? {
+    if params.contains_key("list-type") {
+        log::debug!("list: {location}");
+        let request = list::ListRequest::from_params(&params)?;
+        let registry_ctx = ctx.read_context(&location.registry, auth);
+        return list::list_objects(&registry_ctx, &location, request).await;
+    }
+    log::debug!("get: {location}");
+    if let Some(response) =
+        upstream::check_and_respond(&ctx, &location, &auth, &headers, true).await?
+    {
         return Ok(response);
     }
-    let mut registry_ctx = RegistryOptions::from_server_options(&ctx.options).context(auth);
-    let LayerInfo {
-        reference,
-        digest,
-        content_type,
-    } = get_layer_info(&mut registry_ctx, &location)
-        .await?
-        .ok_or(Error::ReferenceNotFound(location.clone()))?;
-    let blob_stream = registry_ctx
-        .client
-        .pull_blob_stream(&reference, digest.as_str())
-        .await
-        .map_err(Error::OciDistribution)?;
-    Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, content_type)
-        .body(Body::from_stream(blob_stream))
-        .map_err(Error::Http)
+    let registry_ctx = ctx.read_context(&location.registry, auth);
+    let info = match ctx.layer_info(&registry_ctx, &location).await? {
+        Some(info) => info,
+        None => return missing_key(&ctx, location, true),
+    };
+    if let Some(key_pair) = ctx.resigning_key(&location) {
+        return resign::response(&registry_ctx, &headers, &location, &info, key_pair, true).await;
+    }
+    if let Some(response) = check_preconditions(&headers, &conditional::etag(&info), &location)? {
+        return Ok(response);
+    }
+    let range_header = headers
+        .get(header::RANGE)
+        .filter(|_| conditional::range_applies(&headers, &conditional::etag(&info)));
+    let range = match range::parse(range_header, info.size) {
+        RangeRequest::Full => None,
+        RangeRequest::Partial(r) => Some(r),
+        RangeRequest::Unsatisfiable => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", info.size))
+                .body(Body::empty())
+                .map_err(Error::Http);
+        }
+    };
+    let digest = &info.digest;
+    // only full responses fill the cache and are shared, partial ones are read from the cache
+    if let (Some(blobs), Some(r)) = (&ctx.blobs, range)
+        && let Some((mut file, _size)) = blobs.get(digest).await
+    {
+        log::debug!("blob cache hit: {digest}");
+        file.seek(SeekFrom::Start(r.start)).await?;
+        let body = Body::from_stream(ReaderStream::new(file.take(r.length())));
+        return blob_response(&info, range, body);
+    }
+    let start = Instant::now();
+    let body = match range {
+        None => {
+            let fetch = blob_fetch(&registry_ctx, &info);
+            blob_flights::full_body(&ctx, digest, true, fetch).await?
+        }
+        Some(r) => {
+            let response = registry::pull_blob_partial(
+                &registry_ctx,
+                &info.reference,
+                digest,
+                r.start,
+                Some(r.length()),
+            )
+            .await?;
+            if response.status() == StatusCode::PARTIAL_CONTENT {
+                Body::from_stream(response.bytes_stream())
+            } else {
+                log::debug!("registry ignored range request: {digest}");
+                Body::from_stream(range::slice(response.bytes_stream(), r))
+            }
+        }
+    };
+    METRICS.observe_registry("blob", start);
+    blob_response(&info, range, body)
+}
+
+/// Fetches a blob of the registry from offsets
+fn blob_fetch(registry_ctx: &RegistryContext, info: &LayerInfo) -> Fetch {
+    let registry_ctx = registry_ctx.clone();
+    let info = info.clone();
+    Arc::new(move |offset| {
+        let registry_ctx = registry_ctx.clone();
+        let info = info.clone();
+        async move {
+            let (reference, digest) = (&info.reference, info.digest.as_str());
+            if offset == 0 {
+                return registry::pull_blob_stream(&registry_ctx, reference, digest).await;
+            }
+            if offset >= info.size {
+                return Ok(futures::stream::empty().boxed());
+            }
+            let response =
+                registry::pull_blob_partial(&registry_ctx, reference, digest, offset, None).await?;
+            let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+            let stream = response.bytes_stream().map_err(Error::from).boxed();
+            Ok(if partial {
+                stream
+            } else {
+                log::debug!("registry ignored range request: {digest}");
+                blob_flights::skip(stream, offset)
+            })
+        }
+        .boxed()
+    })
+}
+
+fn blob_response(
+    info: &LayerInfo,
+    range: Option<ByteRange>,
+    body: Body,
+) -> Result<Response<Body>, Error> {
+    let builder = Response::builder()
+        .header(header::CONTENT_TYPE, &info.content_type)
+        .header(header::ETAG, conditional::etag(info))
+        .header(header::ACCEPT_RANGES, "bytes");
+    let builder = match range {
+        None => builder
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, info.size),
+        Some(r) => builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_LENGTH, r.length())
+            .header(header::CONTENT_RANGE, r.content_range(info.size)),
+    };
+    builder.body(body).map_err(Error::Http)
+}
+
+/// Response for GET and HEAD requests with `If-Match` or `If-None-Match` not proceeding
+fn check_preconditions(
+    headers: &HeaderMap,
+    etag: &str,
+    location: &OciLocation,
+) -> Result<Option<Response<Body>>, Error> {
+    match conditional::evaluate(headers, etag) {
+        Precondition::Proceed => Ok(None),
+        Precondition::NotModified => Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag)
+            .body(Body::empty())
+            .map(Some)
+            .map_err(Error::Http),
+        Precondition::Failed => Err(Error::PreconditionFailed(location.clone())),
+    }
 }
 
 async fn head_key(
     State(ctx): State<Arc<ServerContext>>,
     location: OciLocation,
     Auth(auth): Auth,
+    headers: HeaderMap,
 ) -> Result<Response<Body>, Error> {
-    log::info!("head: {location}");
-    if let Some(response) = upstream::check_and_redirect(&ctx, &location.key, &auth).await? {
+    log::debug!("head: {location}");
+    if let Some(response) =
+        upstream::check_and_respond(&ctx, &location, &auth, &headers, false).await?
+    {
         return Ok(response);
     }
-    let mut registry_ctx = RegistryOptions::from_server_options(&ctx.options).context(auth);
-    let LayerInfo {
-        reference: _,
-        digest: _,
-        content_type,
-    } = get_layer_info(&mut registry_ctx, &location)
-        .await?
-        .ok_or(Error::ReferenceNotFound(location.clone()))?;
+    let registry_ctx = ctx.read_context(&location.registry, auth);
+    let info = match ctx.layer_info(&registry_ctx, &location).await? {
+        Some(info) => info,
+        None => return missing_key(&ctx, location, false),
+    };
+    if let Some(key_pair) = ctx.resigning_key(&location) {
+        return resign::response(&registry_ctx, &headers, &location, &info, key_pair, false).await;
+    }
+    if let Some(response) = check_preconditions(&headers, &conditional::etag(&info), &location)? {
+        return Ok(response);
+    }
+    blob_response(&info, None, Body::empty())
+}
+
+/// Response for keys not found in the registry
+fn missing_key(
+    ctx: &ServerContext,
+    location: OciLocation,
+    with_body: bool,
+) -> Result<Response<Body>, Error> {
+    let info = cache_info::synthesize(&ctx.options, &location)
+        .ok_or(Error::ReferenceNotFound(location))?;
+    log::debug!("synthesized nix-cache-info:\n{info}");
+    let body = if with_body {
+        Body::from(info.to_string())
+    } else {
+        Body::empty()
+    };
     Response::builder()
         .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, content_type)
-        .body(Body::empty())
+        .header(header::CONTENT_TYPE, NIX_CACHE_INFO_CONTENT_TYPE)
+        .body(body)
         .map_err(Error::Http)
 }
 
@@ -113,19 +468,76 @@ async fn put_key(
     State(ctx): State<Arc<ServerContext>>,
     location: OciLocation,
     Auth(auth): Auth,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
     content_type: Option<TypedHeader<ContentType>>,
-    body: Bytes,
+    body: Body,
 ) -> Result<Response<Body>, Error> {
-    log::info!("put: {location}");
+    log::debug!("put: {location}");
+    if let (Some(id), Some(part_number)) = (params.get("uploadId"), params.get("partNumber")) {
+        return multipart::upload_part(&ctx, location, auth, id, part_number, body).await;
+    }
     // on upstream query for put
-    let mut registry_ctx = RegistryOptions::from_server_options(&ctx.options).context(auth);
-    let item = OciItem {
-        content_type: content_type.map(|TypedHeader(typ)| typ.to_string()),
-        data: body.to_vec(),
-    };
-    registry::put(&mut registry_ctx, &location, item).await?;
+    let mut registry_ctx = ctx.write_context(&location, auth)?;
+    if conditional::if_none_match_any(&headers) {
+        // skip the layer info cache, a stale miss would allow overwriting
+        let mut lookup_ctx = registry_ctx.clone();
+        if get_layer_info(&mut lookup_ctx, &location).await?.is_some() {
+            return Err(Error::PreconditionFailed(location));
+        }
+    }
+    let content_type = content_type.map(|TypedHeader(typ)| typ.to_string());
+    registry::put_stream(
+        &mut registry_ctx,
+        &location,
+        content_type,
+        body.into_data_stream(),
+    )
+    .await?;
+    ctx.layer_infos.invalidate(&location);
     Response::builder()
         .status(StatusCode::OK)
         .body(OK_RESPONSE_BODY.into()) // s3 client will parse the body
         .map_err(Error::Http)
 }
+
+async fn post_key(
+    State(ctx): State<Arc<ServerContext>>,
+    location: OciLocation,
+    Auth(auth): Auth,
+    Query(params): Query<HashMap<String, String>>,
+    content_type: Option<TypedHeader<ContentType>>,
+    body: Body,
+) -> Result<Response<Body>, Error> {
+    log::debug!("post: {location}");
+    let body = axum::body::to_bytes(body, MAX_POST_BODY_SIZE).await?;
+    if params.contains_key("uploads") {
+        let content_type = content_type.map(|TypedHeader(typ)| typ.to_string());
+        multipart::create(&ctx, location, &auth, content_type)
+    } else if let Some(id) = params.get("uploadId") {
+        multipart::complete(&ctx, location, auth, id, &body).await
+    } else {
+        Err(Error::NotImplemented("post object".to_string()))
+    }
+}
+
+async fn delete_key(
+    State(ctx): State<Arc<ServerContext>>,
+    location: OciLocation,
+    Auth(auth): Auth,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response<Body>, Error> {
+    log::debug!("delete: {location}");
+    if let Some(id) = params.get("uploadId") {
+        return multipart::abort(&ctx, location, &auth, id);
+    }
+    let registry_ctx = ctx.write_context(&location, auth)?;
+    let deleted = registry::delete(&registry_ctx, &location).await?;
+    log::debug!("delete {location}: deleted = {deleted}");
+    ctx.layer_infos.invalidate(&location);
+    // s3 reports success for missing keys too
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .map_err(Error::Http)
+}
@@ -0,0 +1,266 @@
+//! Structured access log
+//!
+//! One logfmt line is logged per request under the target `oranc::access`, after the response body is sent.
+//! Handlers add details to the entry of the current request with [`record`].
+//! Requests are identified by `X-Request-Id`, generated if not sent by the client.
+
+use std::{
+    cell::RefCell,
+    fmt::Write,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::Request,
+    middleware::Next,
+    response::Response,
+};
+use http::{HeaderName, HeaderValue, Method, header};
+use hyper::body::{Frame, SizeHint};
+use once_cell::sync::Lazy;
+
+use crate::{error::ErrorMessage, registry::OciLocation};
+
+use super::shutdown::RequestGuard;
+
+pub static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Requests ids sent by clients longer than this are replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+static REQUEST_ID_PREFIX: Lazy<String> = Lazy::new(|| {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    format!("{:x}", nanos ^ std::process::id() as u64)
+});
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    static CURRENT: RefCell<Entry>;
+}
+
+/// Access log entry of a request
+#[derive(Debug, Default)]
+pub struct Entry {
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub location: Option<OciLocation>,
+    /// Tag of the location, the fallback tag found if any
+    pub tag: Option<String>,
+    /// One of `skipped`, `cached_miss`, `miss`, `error`, `redirect` and `proxy`
+    pub upstream: Option<&'static str>,
+    pub error: Option<String>,
+    pub status: u16,
+}
+
+/// Updates the entry of the current request, if called in a request
+pub fn record(f: impl FnOnce(&mut Entry)) {
+    let _ = CURRENT.try_with(|entry| f(&mut entry.borrow_mut()));
+}
+
+/// Middleware assigning request ids and logging requests
+pub async fn layer(mut request: Request, next: Next) -> Response<Body> {
+    let start = Instant::now();
+    let guard = RequestGuard::enter();
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_owned)
+        .unwrap_or_else(|| {
+            let n = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+            format!("{}-{n}", *REQUEST_ID_PREFIX)
+        });
+    let header_value = HeaderValue::from_str(&request_id).expect("visible ascii");
+    request
+        .headers_mut()
+        .insert(REQUEST_ID.clone(), header_value.clone());
+    let entry = Entry {
+        request_id,
+        method: request.method().to_string(),
+        path: request.uri().path().to_owned(),
+        ..Default::default()
+    };
+    let (mut response, mut entry) = CURRENT
+        .scope(RefCell::new(entry), async move {
+            let response = next.run(request).await;
+            let entry = CURRENT.with(|entry| entry.take());
+            (response, entry)
+        })
+        .await;
+    entry.status = response.status().as_u16();
+    if let Some(ErrorMessage(message)) = response.extensions().get() {
+        log::info!("report error, request id '{}': {message}", entry.request_id);
+        entry.error = Some(message.clone());
+    }
+    response
+        .headers_mut()
+        .insert(REQUEST_ID.clone(), header_value);
+
+    let (parts, body) = response.into_parts();
+    let length = if entry.method == Method::HEAD.as_str() {
+        Some(0)
+    } else {
+        body.size_hint().exact().or_else(|| {
+            parts
+                .headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse().ok())
+        })
+    };
+    let body = Body::new(LoggedBody {
+        inner: body,
+        pending: Some((entry, start)),
+        bytes: 0,
+        length,
+        _guard: guard,
+    });
+    Response::from_parts(parts, body)
+}
+
+/// Response body logging its entry when finished or dropped, keeping the size hint
+struct LoggedBody {
+    inner: Body,
+    pending: Option<(Entry, Instant)>,
+    bytes: u64,
+    /// Length of the body sent, if known
+    length: Option<u64>,
+    _guard: RequestGuard,
+}
+
+impl LoggedBody {
+    fn finish(&mut self, complete: bool) {
+        if let Some((entry, start)) = self.pending.take() {
+            log_entry(&entry, self.bytes, start, complete);
+        }
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    this.bytes += data.len() as u64;
+                }
+            }
+            Poll::Ready(Some(Err(e))) => {
+                if let Some((entry, _)) = &mut this.pending {
+                    entry.error = Some(e.to_string());
+                }
+                this.finish(false);
+            }
+            Poll::Ready(None) => this.finish(true),
+            Poll::Pending => (),
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        // bodies of known lengths are not polled to their ends once sent,
+        // otherwise clients went away before the body is sent
+        let complete = self.inner.is_end_stream() || self.length == Some(self.bytes);
+        self.finish(complete);
+    }
+}
+
+fn log_entry(entry: &Entry, bytes: u64, start: Instant, complete: bool) {
+    let mut line = String::new();
+    let mut field = |key: &str, value: &str| {
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(key);
+        line.push('=');
+        push_value(&mut line, value);
+    };
+    field("request_id", &entry.request_id);
+    field("method", &entry.method);
+    field("path", &entry.path);
+    if let Some(location) = &entry.location {
+        field("registry", &location.registry);
+        field("repository", &location.repository);
+        field("key", &location.key);
+    }
+    if let Some(tag) = &entry.tag {
+        field("tag", tag);
+    }
+    if let Some(upstream) = entry.upstream {
+        field("upstream", upstream);
+    }
+    field("status", &entry.status.to_string());
+    field("bytes", &bytes.to_string());
+    field("duration_ms", &start.elapsed().as_millis().to_string());
+    field("complete", &complete.to_string());
+    if let Some(error) = &entry.error {
+        field("error", error);
+    }
+    log::info!(target: "oranc::access", "{line}");
+}
+
+/// Pushes a logfmt value, quoted if needed
+fn push_value(line: &mut String, value: &str) {
+    let quote = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c == ' ' || c == '"' || c == '=' || c == '\\' || c.is_control());
+    if !quote {
+        line.push_str(value);
+        return;
+    }
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(line, "\\u{{{:x}}}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_push_value() {
+        let mut line = String::new();
+        push_value(&mut line, "nar/abc.nar");
+        line.push(' ');
+        push_value(&mut line, "say \"hi\"\n");
+        line.push(' ');
+        push_value(&mut line, "");
+        assert_eq!(line, r#"nar/abc.nar "say \"hi\"\n" """#);
+    }
+}
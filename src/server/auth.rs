@@ -1,14 +1,20 @@
-use std::ops::{Deref, DerefMut};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
 
-use axum::extract::FromRequestParts;
-use data_encoding::BASE64;
+use axum::extract::{FromRef, FromRequestParts, State};
+use data_encoding::{BASE64, HEXLOWER};
 use http::{header::AUTHORIZATION, request::Parts};
 use oci_client::secrets::RegistryAuth;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use sha2::{Digest, Sha256};
 
 use crate::error::Error;
 
+use super::{ServerContext, sigv4};
+
 static AWS_AUTH_PATTERN: Lazy<Regex> =
     Lazy::new(|| Regex::new("^AWS4-HMAC-SHA256 Credential=([^ /,]+)/.*$").unwrap());
 static BASIC_AUTH_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new("^Basic (.*)$").unwrap());
@@ -41,13 +47,14 @@ impl DerefMut for Auth {
 impl<S> FromRequestParts<S> for Auth
 where
     S: Send + Sync,
+    Arc<ServerContext>: FromRef<S>,
 {
     type Rejection = Error;
 
-    async fn from_request_parts(
-        parts: &mut Parts,
-        _state: &S, // authentication are stateless
-    ) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let State(ctx): State<Arc<ServerContext>> = State::from_request_parts(parts, state)
+            .await
+            .expect("infallible");
         let authorization = parts.headers.get(AUTHORIZATION);
 
         match authorization {
@@ -56,6 +63,11 @@ where
                 let s = value
                     .to_str()
                     .map_err(|e| Error::HeaderToStr(AUTHORIZATION, e))?;
+                if !ctx.s3_credentials.is_empty() && s.starts_with(sigv4::ALGORITHM) {
+                    return Ok(sigv4::verify(&ctx.s3_credentials, parts, s)?.into());
+                }
+                // without configured S3 credentials, the access key id carries
+                // base64 encoded registry credentials and the signature is not checked
                 let captures =
                     (BASIC_AUTH_PATTERN.captures(s)).or_else(|| AWS_AUTH_PATTERN.captures(s));
                 let encoded = match &captures {
@@ -76,3 +88,15 @@ where
         }
     }
 }
+
+/// Fingerprint of credentials, usable as a key without keeping secrets around
+pub fn fingerprint(auth: &RegistryAuth) -> String {
+    let material = match auth {
+        RegistryAuth::Anonymous => return "anonymous".to_owned(),
+        RegistryAuth::Basic(username, password) => format!("basic:{username}:{password}"),
+        RegistryAuth::Bearer(token) => format!("bearer:{token}"),
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(material.as_bytes());
+    HEXLOWER.encode(&hasher.finalize())
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use axum::body::Bytes;
+use data_encoding::HEXLOWER;
+use futures::{FutureExt, Stream, StreamExt, channel::oneshot, future::Shared};
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::mpsc::{self, error::TrySendError},
+};
+
+use crate::error::Error;
+
+static DIGEST_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new("^sha256:([0-9a-f]{64})$").unwrap());
+
+/// Chunks buffered for the disk writer, the fill is abandoned when it falls further behind
+const WRITE_BUFFER_CHUNKS: usize = 64;
+
+/// Content-addressed on-disk cache of blobs, keyed by layer digest
+///
+/// Blobs are written to a temporary file while being streamed to the client,
+/// and only committed once the digest of the written content has been verified.
+/// Clients are never slowed down by the disk, fills falling behind are abandoned.
+/// Least recently used blobs are evicted when the total size exceeds `max_size`.
+/// Only one request fills the cache for a digest at a time,
+/// concurrent requests for the same digest share its fetch through `BlobFlights`.
+#[derive(Debug)]
+pub struct BlobCache {
+    dir: PathBuf,
+    max_size: u64,
+    state: Mutex<BlobCacheState>,
+    pending: Mutex<HashMap<String, Shared<oneshot::Receiver<()>>>>,
+}
+
+pub enum Claim {
+    /// The blob is cached, with its size
+    Hit(tokio::fs::File, u64),
+    /// The caller should fetch the blob and fill the cache through the filler
+    Fill(Filler),
+    /// Another request is filling the cache for the blob
+    Wait(Shared<oneshot::Receiver<()>>),
+    /// The blob can not be cached
+    Skip,
+}
+
+/// Exclusive right to fill the cache for a digest, released on drop
+pub struct Filler {
+    cache: Arc<BlobCache>,
+    digest: String,
+    _done: oneshot::Sender<()>,
+}
+
+#[derive(Debug)]
+struct BlobCacheState {
+    total_size: u64,
+    entries: LruCache<String, u64>,
+}
+
+impl BlobCache {
+    pub fn open(dir: &Path, max_size: u64) -> Result<Self, Error> {
+        let cache = Self {
+            dir: dir.to_owned(),
+            max_size,
+            state: Mutex::new(BlobCacheState {
+                total_size: 0,
+                entries: LruCache::unbounded(),
+            }),
+            pending: Mutex::new(HashMap::new()),
+        };
+        // remove partial writes left by previous runs
+        if cache.tmp_dir().exists() {
+            fs::remove_dir_all(cache.tmp_dir())?;
+        }
+        fs::create_dir_all(cache.tmp_dir())?;
+        fs::create_dir_all(cache.blob_dir())?;
+
+        let mut existing = vec![];
+        for entry in fs::read_dir(cache.blob_dir())? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let digest = match entry.file_name().to_str() {
+                Some(hex) if metadata.is_file() => format!("sha256:{hex}"),
+                _ => continue,
+            };
+            if !DIGEST_REGEX.is_match(&digest) {
+                continue;
+            }
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            existing.push((modified, digest, metadata.len()));
+        }
+        existing.sort();
+        for (_, digest, size) in existing {
+            cache.register(digest, size);
+        }
+        log::info!(
+            "blob cache at '{}': {} bytes in use",
+            cache.dir.display(),
+            cache.state.lock().unwrap().total_size
+        );
+        Ok(cache)
+    }
+
+    fn blob_dir(&self) -> PathBuf {
+        self.dir.join("sha256")
+    }
+
+    fn tmp_dir(&self) -> PathBuf {
+        self.dir.join("tmp")
+    }
+
+    fn blob_path(&self, digest: &str) -> Option<PathBuf> {
+        let captures = DIGEST_REGEX.captures(digest)?;
+        Some(self.blob_dir().join(&captures[1]))
+    }
+
+    /// Opens a cached blob, returning the file and its size
+    pub async fn get(&self, digest: &str) -> Option<(tokio::fs::File, u64)> {
+        let path = self.blob_path(digest)?;
+        let size = *self.state.lock().unwrap().entries.get(digest)?;
+        match tokio::fs::File::open(&path).await {
+            Ok(file) => Some((file, size)),
+            Err(e) => {
+                log::warn!("failed to open cached blob '{}': {e}", path.display());
+                self.remove(digest);
+                None
+            }
+        }
+    }
+
+    /// Number of blobs being filled
+    pub fn filling(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Looks up a blob, claiming the right to fill it on miss
+    pub async fn claim(self: &Arc<Self>, digest: &str) -> Claim {
+        if self.blob_path(digest).is_none() {
+            log::debug!("unsupported digest for blob cache: {digest}");
+            return Claim::Skip;
+        }
+        if let Some((file, size)) = self.get(digest).await {
+            return Claim::Hit(file, size);
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(wait) = pending.get(digest) {
+            return Claim::Wait(wait.clone());
+        }
+        let (done, wait) = oneshot::channel();
+        pending.insert(digest.to_owned(), wait.shared());
+        Claim::Fill(Filler {
+            cache: self.clone(),
+            digest: digest.to_owned(),
+            _done: done,
+        })
+    }
+
+    async fn write(
+        &self,
+        digest: &str,
+        mut rx: mpsc::Receiver<Option<Bytes>>,
+    ) -> Result<(), Error> {
+        let path = self.blob_path(digest).expect("checked in claim");
+        let (file, tmp_path) = tempfile::NamedTempFile::new_in(self.tmp_dir())?.into_parts();
+        let mut file = tokio::fs::File::from_std(file);
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        loop {
+            match rx.recv().await {
+                Some(Some(bytes)) => {
+                    hasher.update(&bytes);
+                    size += bytes.len() as u64;
+                    file.write_all(&bytes).await?;
+                }
+                Some(None) => break,
+                None => {
+                    log::debug!("blob stream aborted, discard cache entry: {digest}");
+                    return Ok(());
+                }
+            }
+        }
+        file.sync_all().await?;
+        drop(file);
+        let actual = format!("sha256:{}", HEXLOWER.encode(&hasher.finalize()));
+        if actual != digest {
+            return Err(Error::BlobDigestMismatch {
+                expected: digest.to_owned(),
+                actual,
+            });
+        }
+        tmp_path.persist(&path).map_err(|e| e.error)?;
+        log::debug!("cached blob: {digest}, size = {size}");
+        self.register(digest.to_owned(), size);
+        Ok(())
+    }
+
+    fn register(&self, digest: String, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state.entries.put(digest, size) {
+            state.total_size -= old;
+        }
+        state.total_size += size;
+        while state.total_size > self.max_size {
+            let (digest, size) = match state.entries.pop_lru() {
+                Some(e) => e,
+                None => break,
+            };
+            state.total_size -= size;
+            if let Some(path) = self.blob_path(&digest) {
+                log::debug!("evict cached blob: {digest}");
+                if let Err(e) = fs::remove_file(&path)
+                    && e.kind() != io::ErrorKind::NotFound
+                {
+                    log::warn!("failed to evict cached blob '{}': {e}", path.display());
+                }
+            }
+        }
+    }
+
+    fn remove(&self, digest: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(size) = state.entries.pop(digest) {
+            state.total_size -= size;
+        }
+    }
+}
+
+impl Filler {
+    /// Passes a blob stream through, writing it into the cache on the side
+    pub fn tee<S, E>(self, stream: S) -> impl Stream<Item = Result<Bytes, E>> + use<S, E>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+    {
+        let (tx, rx) = mpsc::channel(WRITE_BUFFER_CHUNKS);
+        tokio::spawn(async move {
+            if let Err(e) = self.cache.write(&self.digest, rx).await {
+                log::warn!("failed to cache blob '{}': {e}", self.digest);
+            }
+        });
+        // `Some(chunk)` carries data, `None` marks a complete stream,
+        // dropping the sender without `None` aborts the write
+        futures::stream::unfold((stream, Some(tx)), |(mut stream, mut tx)| async move {
+            match stream.next().await {
+                Some(Ok(bytes)) => {
+                    if let Some(sender) = &tx
+                        && let Err(TrySendError::Full(_)) = sender.try_send(Some(bytes.clone()))
+                    {
+                        log::debug!("blob cache writer fell behind, abandon the fill");
+                        tx = None;
+                    }
+                    Some((Ok(bytes), (stream, tx)))
+                }
+                Some(Err(e)) => Some((Err(e), (stream, None))),
+                None => {
+                    if let Some(tx) = tx.take() {
+                        let _ = tx.send(None).await;
+                    }
+                    None
+                }
+            }
+        })
+    }
+}
+
+impl Drop for Filler {
+    fn drop(&mut self) {
+        self.cache.pending.lock().unwrap().remove(&self.digest);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{convert::Infallible, time::Duration};
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn sha256_digest(data: &[u8]) -> String {
+        format!("sha256:{}", HEXLOWER.encode(&Sha256::digest(data)))
+    }
+
+    async fn tee_all(cache: &Arc<BlobCache>, digest: &str, chunks: &[&'static [u8]]) {
+        let stream = futures::stream::iter(
+            chunks
+                .iter()
+                .map(|c| Ok::<_, Infallible>(Bytes::from_static(c))),
+        );
+        let filler = match cache.claim(digest).await {
+            Claim::Fill(filler) => filler,
+            _ => panic!("expected to fill the cache"),
+        };
+        let _: Vec<_> = filler.tee(stream).collect().await;
+        // wait for the background writer
+        while cache.pending.lock().unwrap().contains_key(digest) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_verified_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(BlobCache::open(dir.path(), 1024).unwrap());
+        let digest = sha256_digest(b"hello world");
+        tee_all(&cache, &digest, &[b"hello ", b"world"]).await;
+        let (mut file, size) = cache.get(&digest).await.unwrap();
+        let mut content = vec![];
+        file.read_to_end(&mut content).await.unwrap();
+        assert_eq!(size, 11);
+        assert_eq!(content, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn reject_mismatched_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(BlobCache::open(dir.path(), 1024).unwrap());
+        let digest = sha256_digest(b"hello world");
+        tee_all(&cache, &digest, &[b"hello ", b"there"]).await;
+        assert!(cache.get(&digest).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn evict_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(BlobCache::open(dir.path(), 8).unwrap());
+        let first = sha256_digest(b"12345");
+        let second = sha256_digest(b"67890");
+        tee_all(&cache, &first, &[b"12345"]).await;
+        tee_all(&cache, &second, &[b"67890"]).await;
+        assert!(cache.get(&first).await.is_none());
+        assert!(cache.get(&second).await.is_some());
+    }
+}
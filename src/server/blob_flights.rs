@@ -0,0 +1,378 @@
+//! Coalescing of concurrent full fetches of the same blob
+//!
+//! The first request for a digest leads the fetch, and its chunks are broadcast to later requests
+//! joining before the start of the blob leaves the buffer. Buffers are bounded by `buffer_size`,
+//! followers falling further behind, or left by a leader that failed or went away,
+//! fetch the rest of the blob themselves from their offset. This does not depend on the blob cache.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use axum::body::{Body, Bytes};
+use futures::{
+    Stream, StreamExt,
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+};
+use tokio::sync::Notify;
+use tokio_util::io::ReaderStream;
+
+use crate::error::Error;
+
+use super::{ServerContext, blob_cache::Claim};
+
+pub type BlobStream = BoxStream<'static, Result<Bytes, Error>>;
+
+/// Fetches a blob from an offset
+pub type Fetch = Arc<dyn Fn(u64) -> BoxFuture<'static, Result<BlobStream, Error>> + Send + Sync>;
+
+#[derive(Debug)]
+pub struct BlobFlights {
+    buffer_size: u64,
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+}
+
+pub enum Role {
+    /// The caller fetches the blob and broadcasts it through the leader
+    Lead(Leader),
+    /// The blob is received from a concurrent fetch
+    Follow(Follower),
+    /// The blob can not be shared, the caller fetches it alone
+    Alone,
+}
+
+#[derive(Debug, Default)]
+struct Flight {
+    state: Mutex<FlightState>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct FlightState {
+    /// Buffered chunks with their offsets
+    chunks: VecDeque<(u64, Bytes)>,
+    /// Offset of the first buffered chunk
+    start: u64,
+    /// Offset after the last chunk
+    end: u64,
+    closed: bool,
+    complete: bool,
+}
+
+/// Broadcasts a fetch, followers are left to fetch themselves if dropped before completion
+pub struct Leader {
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+    digest: String,
+    flight: Arc<Flight>,
+    buffer_size: u64,
+}
+
+pub struct Follower {
+    flight: Arc<Flight>,
+    offset: u64,
+}
+
+enum Next {
+    Chunk(Bytes),
+    End,
+    /// The rest from the offset has to be fetched
+    Resume(u64),
+}
+
+impl BlobFlights {
+    /// Flights buffering up to `buffer_size` bytes each, 0 disables coalescing
+    pub fn new(buffer_size: u64) -> Self {
+        Self {
+            buffer_size,
+            flights: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Joins the fetch of `digest` in flight, or leads a new one
+    pub fn join(&self, digest: &str) -> Role {
+        if self.buffer_size == 0 {
+            return Role::Alone;
+        }
+        let mut flights = self.flights.lock().unwrap();
+        if let Some(flight) = flights.get(digest) {
+            let state = flight.state.lock().unwrap();
+            return if state.start == 0 && !state.closed {
+                log::debug!("join concurrent fetch: {digest}");
+                Role::Follow(Follower {
+                    flight: flight.clone(),
+                    offset: 0,
+                })
+            } else {
+                Role::Alone
+            };
+        }
+        let flight = Arc::new(Flight::default());
+        flights.insert(digest.to_owned(), flight.clone());
+        Role::Lead(Leader {
+            flights: self.flights.clone(),
+            digest: digest.to_owned(),
+            flight,
+            buffer_size: self.buffer_size,
+        })
+    }
+
+    /// Number of fetches in flight
+    pub fn in_flight(&self) -> usize {
+        self.flights.lock().unwrap().len()
+    }
+}
+
+impl Leader {
+    /// Passes a blob stream through, broadcasting it to followers
+    pub fn tee<S, E>(self, stream: S) -> impl Stream<Item = Result<Bytes, E>> + use<S, E>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+    {
+        stream::unfold(
+            (stream, Some(self)),
+            |(mut stream, mut leader)| async move {
+                match stream.next().await {
+                    Some(Ok(bytes)) => {
+                        if let Some(leader) = &leader {
+                            leader.push(bytes.clone());
+                        }
+                        Some((Ok(bytes), (stream, leader)))
+                    }
+                    // followers fetch the rest themselves once the leader is dropped
+                    Some(Err(e)) => Some((Err(e), (stream, None))),
+                    None => {
+                        if let Some(leader) = leader.take() {
+                            leader.close(true);
+                        }
+                        None
+                    }
+                }
+            },
+        )
+    }
+
+    fn push(&self, bytes: Bytes) {
+        let mut state = self.flight.state.lock().unwrap();
+        let offset = state.end;
+        state.end += bytes.len() as u64;
+        state.chunks.push_back((offset, bytes));
+        while state.end - state.start > self.buffer_size {
+            state.chunks.pop_front();
+            state.start = state
+                .chunks
+                .front()
+                .map_or(state.end, |(offset, _)| *offset);
+        }
+        drop(state);
+        self.flight.notify.notify_waiters();
+    }
+
+    fn close(&self, complete: bool) {
+        let mut state = self.flight.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+        state.closed = true;
+        state.complete = complete;
+        drop(state);
+        let mut flights = self.flights.lock().unwrap();
+        if flights
+            .get(&self.digest)
+            .is_some_and(|f| Arc::ptr_eq(f, &self.flight))
+        {
+            flights.remove(&self.digest);
+        }
+        drop(flights);
+        self.flight.notify.notify_waiters();
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.close(false);
+    }
+}
+
+impl Follower {
+    async fn next(&mut self) -> Next {
+        loop {
+            let notified = self.flight.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let state = self.flight.state.lock().unwrap();
+                if self.offset < state.start {
+                    return Next::Resume(self.offset);
+                }
+                if self.offset < state.end {
+                    let index = state
+                        .chunks
+                        .partition_point(|(offset, _)| *offset < self.offset);
+                    let bytes = state.chunks[index].1.clone();
+                    self.offset += bytes.len() as u64;
+                    return Next::Chunk(bytes);
+                }
+                match (state.closed, state.complete) {
+                    (true, true) => return Next::End,
+                    (true, false) => return Next::Resume(self.offset),
+                    (false, _) => (),
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// The blob received from the leader, fetched with `fetch` from where the leader left
+    pub fn stream(self, fetch: Fetch) -> BlobStream {
+        enum State {
+            Follow(Follower),
+            Resumed(BlobStream),
+            Done,
+        }
+        stream::unfold((State::Follow(self), fetch), |(state, fetch)| async move {
+            match state {
+                State::Follow(mut follower) => match follower.next().await {
+                    Next::Chunk(bytes) => Some((Ok(bytes), (State::Follow(follower), fetch))),
+                    Next::End => None,
+                    Next::Resume(offset) => {
+                        log::debug!("fetch the rest of the blob from offset {offset}");
+                        match fetch(offset).await {
+                            Ok(mut stream) => {
+                                let item = stream.next().await?;
+                                Some((item, (State::Resumed(stream), fetch)))
+                            }
+                            Err(e) => Some((Err(e), (State::Done, fetch))),
+                        }
+                    }
+                },
+                State::Resumed(mut stream) => {
+                    let item = stream.next().await?;
+                    Some((item, (State::Resumed(stream), fetch)))
+                }
+                State::Done => None,
+            }
+        })
+        .boxed()
+    }
+}
+
+/// Body of a full blob, from the blob cache if `cached`, a concurrent fetch of it, or `fetch`
+pub async fn full_body(
+    ctx: &ServerContext,
+    digest: &str,
+    cached: bool,
+    fetch: Fetch,
+) -> Result<Body, Error> {
+    let blobs = ctx.blobs.as_ref().filter(|_| cached);
+    if let Some(blobs) = blobs
+        && let Some((file, _size)) = blobs.get(digest).await
+    {
+        log::debug!("blob cache hit: {digest}");
+        return Ok(Body::from_stream(ReaderStream::new(file)));
+    }
+    let role = ctx.blob_flights.join(digest);
+    if let Role::Follow(follower) = role {
+        return Ok(Body::from_stream(follower.stream(fetch)));
+    }
+    let filler = match blobs {
+        Some(blobs) => match blobs.claim(digest).await {
+            Claim::Hit(file, _size) => return Ok(Body::from_stream(ReaderStream::new(file))),
+            Claim::Fill(filler) => Some(filler),
+            Claim::Wait(_) | Claim::Skip => None,
+        },
+        None => None,
+    };
+    let stream = fetch(0).await?;
+    let stream = match filler {
+        Some(filler) => filler.tee(stream).boxed(),
+        None => stream,
+    };
+    Ok(match role {
+        Role::Lead(leader) => Body::from_stream(leader.tee(stream)),
+        _ => Body::from_stream(stream),
+    })
+}
+
+/// Skips the first `n` bytes of a stream, for sources ignoring range requests
+pub fn skip(stream: BlobStream, n: u64) -> BlobStream {
+    stream
+        .scan(n, |remaining, item| {
+            let item = item.map(|mut bytes| {
+                let skipped = (*remaining).min(bytes.len() as u64);
+                *remaining -= skipped;
+                bytes.split_off(skipped as usize)
+            });
+            future::ready(Some(item))
+        })
+        .filter(|item| future::ready(!matches!(item, Ok(bytes) if bytes.is_empty())))
+        .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{FutureExt, TryStreamExt};
+
+    use super::*;
+
+    fn chunks(data: &[&'static [u8]]) -> BlobStream {
+        let chunks: Vec<_> = data.iter().map(|c| Ok(Bytes::from_static(c))).collect();
+        stream::iter(chunks).boxed()
+    }
+
+    /// Fetches `data` from offsets
+    fn fetch(data: &'static [u8]) -> Fetch {
+        Arc::new(move |offset| async move { Ok(chunks(&[&data[offset as usize..]])) }.boxed())
+    }
+
+    async fn collect(stream: BlobStream) -> Vec<u8> {
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    #[tokio::test]
+    async fn share_concurrent_fetch() {
+        let flights = BlobFlights::new(1024);
+        let Role::Lead(leader) = flights.join("sha256:a") else {
+            panic!("expected to lead");
+        };
+        let Role::Follow(follower) = flights.join("sha256:a") else {
+            panic!("expected to follow");
+        };
+        let (led, followed) = tokio::join!(
+            collect(leader.tee(chunks(&[b"hello ", b"world"])).boxed()),
+            collect(follower.stream(fetch(b"nothing here")))
+        );
+        assert_eq!(led, b"hello world");
+        assert_eq!(followed, b"hello world");
+        assert_eq!(flights.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn resume_after_leader() {
+        let flights = BlobFlights::new(4);
+        let Role::Lead(leader) = flights.join("sha256:a") else {
+            panic!("expected to lead");
+        };
+        let Role::Follow(follower) = flights.join("sha256:a") else {
+            panic!("expected to follow");
+        };
+        let mut led = leader.tee(chunks(&[b"hel", b"lo ", b"world"])).boxed();
+        led.next().await.unwrap().unwrap();
+        led.next().await.unwrap().unwrap();
+        // the start of the blob is no longer buffered
+        assert!(matches!(flights.join("sha256:a"), Role::Alone));
+        // the follower lags behind the buffer, then the leader goes away
+        drop(led);
+        let followed = collect(follower.stream(fetch(b"hello world"))).await;
+        assert_eq!(followed, b"hello world");
+        assert_eq!(flights.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn skip_bytes() {
+        let skipped = collect(skip(chunks(&[b"hel", b"lo ", b"world"]), 4)).await;
+        assert_eq!(skipped, b"o world");
+    }
+}
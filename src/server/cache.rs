@@ -0,0 +1,129 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+
+use crate::registry::{LayerInfo, OciLocation};
+
+/// Bounded LRU cache of layer information
+///
+/// Entries are keyed by location and credential fingerprint,
+/// so that a client never sees results fetched with other credentials.
+/// Missing keys are cached as well, with their own TTL.
+#[derive(Debug)]
+pub struct LayerInfoCache {
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    entries: Option<Mutex<LruCache<CacheKey, CacheEntry>>>,
+}
+
+type CacheKey = (OciLocation, String);
+
+#[derive(Debug)]
+struct CacheEntry {
+    info: Option<LayerInfo>,
+    expires: Instant,
+}
+
+impl LayerInfoCache {
+    pub fn new(capacity: usize, positive_ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            positive_ttl,
+            negative_ttl,
+            entries: NonZeroUsize::new(capacity).map(|c| Mutex::new(LruCache::new(c))),
+        }
+    }
+
+    /// Returns `Some(info)` on cache hit, where `info` is `None` for a cached miss
+    pub fn get(&self, location: &OciLocation, fingerprint: &str) -> Option<Option<LayerInfo>> {
+        let mut entries = self.entries.as_ref()?.lock().unwrap();
+        let key = (location.clone(), fingerprint.to_owned());
+        match entries.get(&key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.info.clone()),
+            Some(_) => {
+                entries.pop(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, location: OciLocation, fingerprint: String, info: Option<LayerInfo>) {
+        let entries = match &self.entries {
+            Some(e) => e,
+            None => return,
+        };
+        let ttl = if info.is_some() {
+            self.positive_ttl
+        } else {
+            self.negative_ttl
+        };
+        if ttl.is_zero() {
+            return;
+        }
+        let entry = CacheEntry {
+            info,
+            expires: Instant::now() + ttl,
+        };
+        entries.lock().unwrap().put((location, fingerprint), entry);
+    }
+
+    /// Drops cached entries of the location for all credentials
+    pub fn invalidate(&self, location: &OciLocation) {
+        let entries = match &self.entries {
+            Some(e) => e,
+            None => return,
+        };
+        let mut entries = entries.lock().unwrap();
+        let keys: Vec<_> = entries
+            .iter()
+            .filter(|((l, _), _)| l == location)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in keys {
+            entries.pop(&key);
+        }
+    }
+}
+
+/// Bounded LRU cache of keys missing in all upstream caches
+#[derive(Debug)]
+pub struct UpstreamMissCache {
+    ttl: Duration,
+    entries: Option<Mutex<LruCache<String, Instant>>>,
+}
+
+impl UpstreamMissCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        let entries = if ttl.is_zero() {
+            None
+        } else {
+            NonZeroUsize::new(capacity).map(|c| Mutex::new(LruCache::new(c)))
+        };
+        Self { ttl, entries }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        let mut entries = match &self.entries {
+            Some(e) => e.lock().unwrap(),
+            None => return false,
+        };
+        match entries.get(key) {
+            Some(expires) if *expires > Instant::now() => true,
+            Some(_) => {
+                entries.pop(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert(&self, key: String) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().put(key, Instant::now() + self.ttl);
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use crate::{
+    nix::{NIX_CACHE_INFO_KEY, NixCacheInfo},
+    options::ServerOptions,
+    registry::OciLocation,
+};
+
+/// Builds nix-cache-info for repositories that have not been initialized
+///
+/// Returns `None` if synthesizing is disabled or the location is not nix-cache-info.
+pub fn synthesize(options: &ServerOptions, location: &OciLocation) -> Option<NixCacheInfo> {
+    if !options.synthesize_nix_cache_info || location.key != NIX_CACHE_INFO_KEY {
+        return None;
+    }
+    let mut info = NixCacheInfo {
+        store_dir: options.cache_info_store_dir.clone(),
+        want_mass_query: !options.cache_info_no_mass_query,
+        priority: options.cache_info_priority,
+    };
+    let repository = format!("{}/{}", location.registry, location.repository);
+    for o in options
+        .repository_cache_info
+        .iter()
+        .filter(|o| o.repository == repository)
+    {
+        if let Some(store_dir) = &o.store_dir {
+            info.store_dir = store_dir.clone();
+        }
+        if let Some(want_mass_query) = o.want_mass_query {
+            info.want_mass_query = want_mass_query;
+        }
+        if let Some(priority) = o.priority {
+            info.priority = priority;
+        }
+    }
+    Some(info)
+}
@@ -0,0 +1,94 @@
+//! Conditional requests with layer digests as entity tags
+
+use http::{HeaderMap, HeaderValue, header};
+
+use crate::registry::LayerInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+/// Strong entity tag of a layer
+pub fn etag(info: &LayerInfo) -> String {
+    format!("\"{}\"", info.digest)
+}
+
+/// Evaluates `If-Match` and `If-None-Match` of a GET or HEAD request, in the order of RFC 9110
+pub fn evaluate(headers: &HeaderMap, etag: &str) -> Precondition {
+    if let Some(if_match) = headers.get(header::IF_MATCH)
+        && !matches(if_match, etag, false)
+    {
+        return Precondition::Failed;
+    }
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH)
+        && matches(if_none_match, etag, true)
+    {
+        return Precondition::NotModified;
+    }
+    Precondition::Proceed
+}
+
+/// Whether a range request should be served as a range, according to `If-Range`
+pub fn range_applies(headers: &HeaderMap, etag: &str) -> bool {
+    match headers.get(header::IF_RANGE) {
+        None => true,
+        // dates never match, layers have no modification time
+        Some(if_range) => if_range.to_str().is_ok_and(|v| v.trim() == etag),
+    }
+}
+
+/// Whether `If-None-Match: *` is present, asking not to overwrite existing keys
+pub fn if_none_match_any(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim() == "*")
+}
+
+fn matches(value: &HeaderValue, etag: &str, weak: bool) -> bool {
+    let value = match value.to_str() {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    value.split(',').map(str::trim).any(|tag| {
+        if tag == "*" {
+            return true;
+        }
+        match tag.strip_prefix("W/") {
+            Some(weak_tag) => weak && weak_tag == etag,
+            None => tag == etag,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let etag = "\"sha256:00\"";
+        assert_eq!(evaluate(&HeaderMap::new(), etag), Precondition::Proceed);
+        let h = headers(header::IF_NONE_MATCH, "\"other\", \"sha256:00\"");
+        assert_eq!(evaluate(&h, etag), Precondition::NotModified);
+        let h = headers(header::IF_NONE_MATCH, "W/\"sha256:00\"");
+        assert_eq!(evaluate(&h, etag), Precondition::NotModified);
+        let h = headers(header::IF_NONE_MATCH, "\"other\"");
+        assert_eq!(evaluate(&h, etag), Precondition::Proceed);
+        let h = headers(header::IF_MATCH, "\"other\"");
+        assert_eq!(evaluate(&h, etag), Precondition::Failed);
+        let h = headers(header::IF_MATCH, "W/\"sha256:00\"");
+        assert_eq!(evaluate(&h, etag), Precondition::Failed);
+        let h = headers(header::IF_MATCH, "*");
+        assert_eq!(evaluate(&h, etag), Precondition::Proceed);
+    }
+}
@@ -0,0 +1,76 @@
+use std::{collections::HashMap, env, fmt, fs, path::Path};
+
+use oci_client::secrets::RegistryAuth;
+
+use crate::error::Error;
+
+pub const REGISTRY_CREDENTIALS_ENV: &str = "ORANC_REGISTRY_CREDENTIALS";
+
+/// Credentials the server uses on behalf of anonymous readers, keyed by registry
+#[derive(Default)]
+pub struct RegistryCredentials(HashMap<String, RegistryAuth>);
+
+impl RegistryCredentials {
+    /// Loads credentials from a file and the environment variable `ORANC_REGISTRY_CREDENTIALS`
+    ///
+    /// Both contain whitespace separated `REGISTRY=USERNAME:PASSWORD` entries,
+    /// lines starting with `#` in the file are ignored.
+    /// Entries from the environment take precedence.
+    pub fn load(file: Option<&Path>) -> Result<Self, Error> {
+        let mut credentials = HashMap::new();
+        if let Some(path) = file {
+            let content = fs::read_to_string(path)?;
+            for line in content.lines() {
+                if line.trim_start().starts_with('#') {
+                    continue;
+                }
+                parse_entries(line, &mut credentials)?;
+            }
+        }
+        match env::var(REGISTRY_CREDENTIALS_ENV) {
+            Ok(content) => parse_entries(&content, &mut credentials)?,
+            Err(env::VarError::NotPresent) => (),
+            Err(e) => return Err(Error::InvalidRegistryCredentialsEnv(e)),
+        }
+        for registry in credentials.keys() {
+            log::info!("loaded server credentials for registry '{registry}'");
+        }
+        Ok(Self(credentials))
+    }
+
+    pub fn get(&self, registry: &str) -> Option<&RegistryAuth> {
+        self.0.get(registry)
+    }
+
+    pub fn contains(&self, registry: &str) -> bool {
+        self.0.contains_key(registry)
+    }
+}
+
+fn parse_entries(s: &str, credentials: &mut HashMap<String, RegistryAuth>) -> Result<(), Error> {
+    for entry in s.split_whitespace() {
+        // do not report the password
+        let invalid = || {
+            Error::InvalidRegistryCredentials(
+                entry.split('=').next().unwrap_or_default().to_owned(),
+            )
+        };
+        let (registry, user_password) = entry.split_once('=').ok_or_else(invalid)?;
+        let (username, password) = user_password.split_once(':').ok_or_else(invalid)?;
+        if registry.is_empty() || username.is_empty() {
+            return Err(invalid());
+        }
+        credentials.insert(
+            registry.to_owned(),
+            RegistryAuth::Basic(username.to_owned(), password.to_owned()),
+        );
+    }
+    Ok(())
+}
+
+impl fmt::Debug for RegistryCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print secrets
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
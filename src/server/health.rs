@@ -0,0 +1,92 @@
+//! Liveness and readiness endpoints
+//!
+//! Readiness is checked by looking up `nix-cache-info` in the repositories of `--ready-check`,
+//! with server credentials if configured. A missing `nix-cache-info` still means the registry is reachable.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use futures::future;
+use http::StatusCode;
+use oci_client::secrets::RegistryAuth;
+use tokio::{sync::Mutex, time};
+
+use crate::{
+    nix::NIX_CACHE_INFO_KEY,
+    registry::{OciLocation, get_layer_info},
+};
+
+use super::ServerContext;
+
+/// Last readiness result, shared by concurrent probes
+#[derive(Debug, Default)]
+pub struct Readiness {
+    last: Mutex<Option<(Instant, Result<(), String>)>>,
+}
+
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+pub async fn readyz(State(ctx): State<Arc<ServerContext>>) -> Response<Body> {
+    // load balancers stop routing to a draining server
+    if ctx.shutdown.is_triggered() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down").into_response();
+    }
+    let ttl = Duration::from_secs(ctx.options.ready_check_cache_ttl);
+    let mut last = ctx.readiness.last.lock().await;
+    let result = match &*last {
+        Some((checked, result)) if checked.elapsed() < ttl => result.clone(),
+        _ => {
+            let result = check(&ctx).await;
+            *last = Some((Instant::now(), result.clone()));
+            result
+        }
+    };
+    match result {
+        Ok(()) => (StatusCode::OK, "ok".to_owned()).into_response(),
+        Err(message) => (StatusCode::SERVICE_UNAVAILABLE, message).into_response(),
+    }
+}
+
+async fn check(ctx: &ServerContext) -> Result<(), String> {
+    let timeout = Duration::from_secs(ctx.options.ready_check_timeout);
+    let checks = ctx.options.ready_check.iter().map(|repository| async move {
+        let (registry, repository) = repository
+            .split_once('/')
+            .ok_or_else(|| format!("{repository}: invalid repository"))?;
+        let location = OciLocation {
+            registry: registry.to_owned(),
+            repository: repository.to_owned(),
+            key: NIX_CACHE_INFO_KEY.to_owned(),
+        };
+        // bypass the layer info cache, which may hide registry failures
+        let mut registry_ctx = ctx.read_context(registry, RegistryAuth::Anonymous);
+        let failure =
+            match time::timeout(timeout, get_layer_info(&mut registry_ctx, &location)).await {
+                Ok(Ok(_)) => return Ok(()),
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => "timed out".to_owned(),
+            };
+        // details are only logged, the response may be public
+        log::warn!("readiness check of {location} failed: {failure}");
+        Err(format!("{location}: unavailable"))
+    });
+    let failures: Vec<String> = future::join_all(checks)
+        .await
+        .into_iter()
+        .filter_map(Result::err)
+        .collect();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures.join("\n"))
+    }
+}
@@ -0,0 +1,200 @@
+//! S3 ListObjectsV2 backed by registry tags
+//!
+//! Tags are read page by page from the registry, continuation tokens are the registry cursor,
+//! the last tag consumed. Keys are sorted within each page only, since tags are not ordered like
+//! their keys. Tags not decodable to keys are skipped.
+
+use std::collections::{BTreeSet, HashMap};
+
+use axum::{body::Body, response::Response};
+
+use crate::{
+    error::Error,
+    registry::{self, OciLocation, RegistryContext},
+    xml::xml_escape,
+};
+
+use super::s3::{XML_NAMESPACE, bucket, xml_response};
+
+const DEFAULT_MAX_KEYS: usize = 1000;
+const TAGS_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Default)]
+pub struct ListRequest {
+    pub prefix: String,
+    pub continuation_token: Option<String>,
+    pub start_after: Option<String>,
+    pub max_keys: usize,
+}
+
+impl ListRequest {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, Error> {
+        match params.get("list-type").map(String::as_str) {
+            Some("2") => (),
+            other => {
+                return Err(Error::NotImplemented(format!(
+                    "list objects with list-type {other:?}"
+                )));
+            }
+        }
+        let max_keys = match params.get("max-keys") {
+            Some(n) => n
+                .parse::<usize>()
+                .map_err(|_| Error::InvalidListParameter("max-keys", n.clone()))?
+                .min(DEFAULT_MAX_KEYS),
+            None => DEFAULT_MAX_KEYS,
+        };
+        Ok(Self {
+            prefix: params.get("prefix").cloned().unwrap_or_default(),
+            continuation_token: params
+                .get("continuation-token")
+                .filter(|t| !t.is_empty())
+                .cloned(),
+            start_after: params.get("start-after").filter(|s| !s.is_empty()).cloned(),
+            max_keys,
+        })
+    }
+}
+
+/// Keys of a page listed from the cursor of `request`, and the cursor of the next page if any
+async fn list_page(
+    registry_ctx: &RegistryContext,
+    location: &OciLocation,
+    request: &ListRequest,
+) -> Result<(Vec<String>, Option<String>), Error> {
+    let encoding_options = &registry_ctx.options.encoding_options;
+    let tag_to_key = |tag: &str| match encoding_options.tag_to_key(tag) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            log::debug!("skip tag '{tag}' in listing: {e}");
+            None
+        }
+    };
+    let mut keys = BTreeSet::new();
+    let mut last = request.continuation_token.clone();
+    loop {
+        let tags =
+            registry::list_tags(registry_ctx, location, TAGS_PAGE_SIZE, last.as_deref()).await?;
+        let cursor = last.clone();
+        if consume(&tags, &mut last, &mut keys, request, tag_to_key)
+            || keys.len() == request.max_keys
+        {
+            return Ok((keys.into_iter().collect(), last));
+        }
+        // registries ignoring `last` return the same page again
+        if tags.len() < TAGS_PAGE_SIZE || last == cursor {
+            return Ok((keys.into_iter().collect(), None));
+        }
+    }
+}
+
+/// Adds keys of `tags` after the cursor `last` until `max_keys` keys are listed,
+/// moving the cursor past consumed tags, returns whether tags are left
+fn consume(
+    tags: &[String],
+    last: &mut Option<String>,
+    keys: &mut BTreeSet<String>,
+    request: &ListRequest,
+    tag_to_key: impl Fn(&str) -> Option<String>,
+) -> bool {
+    let cursor = last.clone();
+    let mut tags = tags
+        .iter()
+        .filter(|tag| cursor.as_ref().is_none_or(|c| *tag > c))
+        .peekable();
+    while keys.len() < request.max_keys {
+        let Some(tag) = tags.next() else {
+            return false;
+        };
+        *last = Some(tag.clone());
+        // fallback tags of a key decode to the same key
+        if let Some(key) = tag_to_key(tag).filter(|key| {
+            key.starts_with(&request.prefix) && request.start_after.as_ref().is_none_or(|s| key > s)
+        }) {
+            keys.insert(key);
+        }
+    }
+    tags.peek().is_some()
+}
+
+/// ListObjectsV2
+pub async fn list_objects(
+    registry_ctx: &RegistryContext,
+    location: &OciLocation,
+    request: ListRequest,
+) -> Result<Response<Body>, Error> {
+    let (keys, next_token) = list_page(registry_ctx, location, &request).await?;
+
+    let mut document = format!(
+        "<ListBucketResult xmlns=\"{XML_NAMESPACE}\">\
+         <Name>{}</Name><Prefix>{}</Prefix><KeyCount>{}</KeyCount>\
+         <MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+        xml_escape(bucket(location)),
+        xml_escape(&request.prefix),
+        keys.len(),
+        request.max_keys,
+        next_token.is_some(),
+    );
+    if let Some(token) = &request.continuation_token {
+        document.push_str(&format!(
+            "<ContinuationToken>{}</ContinuationToken>",
+            xml_escape(token)
+        ));
+    }
+    if let Some(token) = &next_token {
+        document.push_str(&format!(
+            "<NextContinuationToken>{}</NextContinuationToken>",
+            xml_escape(token)
+        ));
+    }
+    if let Some(start_after) = &request.start_after {
+        document.push_str(&format!(
+            "<StartAfter>{}</StartAfter>",
+            xml_escape(start_after)
+        ));
+    }
+    for key in keys {
+        document.push_str(&format!(
+            "<Contents><Key>{}</Key><StorageClass>STANDARD</StorageClass></Contents>",
+            xml_escape(&key)
+        ));
+    }
+    document.push_str("</ListBucketResult>");
+    xml_response(document)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn consume_tags() {
+        let tags = ["a1", "b1", "b2", "c1", "x"].map(str::to_owned);
+        // tags decode to their first letter, "x" is not decodable
+        let tag_to_key = |tag: &str| Some(&tag[..1]).filter(|k| *k != "x").map(str::to_owned);
+        let mut request = ListRequest {
+            max_keys: 2,
+            ..Default::default()
+        };
+        let mut keys = BTreeSet::new();
+        let mut last = None;
+        assert!(consume(&tags, &mut last, &mut keys, &request, tag_to_key));
+        assert_eq!(keys, BTreeSet::from(["a".to_owned(), "b".to_owned()]));
+        assert_eq!(last.as_deref(), Some("b1"));
+
+        // the next page starts after the cursor
+        let mut keys = BTreeSet::new();
+        request.max_keys = 3;
+        assert!(!consume(&tags, &mut last, &mut keys, &request, tag_to_key));
+        assert_eq!(keys, BTreeSet::from(["b".to_owned(), "c".to_owned()]));
+        assert_eq!(last.as_deref(), Some("x"));
+
+        let mut keys = BTreeSet::new();
+        let mut last = None;
+        request.max_keys = 2;
+        request.start_after = Some("a".to_owned());
+        assert!(consume(&tags, &mut last, &mut keys, &request, tag_to_key));
+        assert_eq!(keys, BTreeSet::from(["b".to_owned(), "c".to_owned()]));
+        assert_eq!(last.as_deref(), Some("c1"));
+    }
+}
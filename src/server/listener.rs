@@ -0,0 +1,137 @@
+//! Listening sockets of the server
+//!
+//! A socket passed by systemd socket activation (`LISTEN_FDS`) takes precedence over `--listen`.
+
+use std::{
+    env, fs, io,
+    os::{
+        fd::{FromRawFd, IntoRawFd, RawFd},
+        unix::fs::FileTypeExt,
+    },
+    path::Path,
+};
+
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+
+use crate::{error::Error, options::Listen};
+
+/// First file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+pub async fn bind(listen: &Listen) -> Result<Listener, Error> {
+    if let Some(listener) = activated()? {
+        return Ok(listener);
+    }
+    let bind_error = |e| Error::Bind(listen.to_string(), e);
+    let listener = match listen {
+        Listen::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr).await.map_err(bind_error)?),
+        Listen::Unix(path) => {
+            remove_stale(path).await.map_err(bind_error)?;
+            Listener::Unix(UnixListener::bind(path).map_err(bind_error)?)
+        }
+    };
+    log::info!("listening on {listen}");
+    Ok(listener)
+}
+
+/// Removes a socket left by a previous run at `path`
+///
+/// Other files are kept, binding fails on them. A socket accepting connections
+/// belongs to a running instance and is kept, connecting is refused on a stale one.
+async fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => (),
+        _ => return Ok(()),
+    }
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "socket in use by a running instance",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            log::info!("remove stale socket '{}'", path.display());
+            fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The socket passed by systemd, if any
+fn activated() -> Result<Option<Listener>, Error> {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .unwrap_or(0);
+    if !for_us || fds == 0 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        log::warn!("{fds} sockets passed by systemd, only the first one is used");
+    }
+    let activation_error = |e| Error::Bind("systemd socket".to_owned(), e);
+    // SAFETY: systemd passes the sockets starting from fd 3 to this process,
+    // and nothing else owns them
+    let std_listener = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    let listener = match std_listener.local_addr() {
+        Ok(addr) => {
+            log::info!("listening on systemd socket {addr}");
+            std_listener
+                .set_nonblocking(true)
+                .map_err(activation_error)?;
+            Listener::Tcp(TcpListener::from_std(std_listener).map_err(activation_error)?)
+        }
+        // not an internet socket
+        Err(_) => {
+            let fd = std_listener.into_raw_fd();
+            // SAFETY: ownership of the fd is moved out of the tcp listener above
+            let std_listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            let addr = std_listener.local_addr().map_err(activation_error)?;
+            log::info!("listening on systemd socket {addr:?}");
+            std_listener
+                .set_nonblocking(true)
+                .map_err(activation_error)?;
+            Listener::Unix(UnixListener::from_std(std_listener).map_err(activation_error)?)
+        }
+    };
+    Ok(Some(listener))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn keep_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("oranc.sock");
+        fs::write(&path, "not a socket").unwrap();
+        remove_stale(&path).await.unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+
+        let listener = UnixListener::bind(&path);
+        assert!(listener.is_err());
+    }
+
+    #[tokio::test]
+    async fn remove_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("oranc.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        assert_eq!(
+            remove_stale(&path).await.unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+        drop(listener);
+        remove_stale(&path).await.unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,289 @@
+//! Write-through mirroring of narinfos served from upstreams
+//!
+//! After an upstream hit of a narinfo for an authenticated client,
+//! the narinfo and its NAR are copied into the repository in the background, with the credentials of the client.
+//! The NAR is pushed first, so that the repository never has a narinfo without its NAR.
+//! Upstreams are not trusted to name keys: only NARs at `nar/{hash}.nar[.{ext}]` matching
+//! `FileHash` and `FileSize` of the narinfo are mirrored.
+
+use std::{collections::HashSet, sync::Arc, sync::Mutex};
+
+use futures::{Stream, StreamExt};
+use nix_base32::to_nix_base32;
+use oci_client::secrets::RegistryAuth;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::Error,
+    nix::{NAR_CONTENT_TYPE, NARINFO_CONTENT_TYPE, NarInfo},
+    registry::{self, OciItem, OciLocation},
+};
+
+use super::{
+    ServerContext,
+    upstream::{UpstreamHit, nar_digest, upstream_url},
+};
+
+static NAR_KEY_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new("^nar/[0123456789abcdfghijklmnpqrsvwxyz]{52}\\.nar(\\.[a-z0-9]+)?$").unwrap()
+});
+
+/// Locations being mirrored, a location is mirrored by one task at a time
+#[derive(Debug, Default)]
+pub struct Mirrors {
+    in_flight: Mutex<HashSet<OciLocation>>,
+}
+
+impl Mirrors {
+    /// Number of locations being mirrored
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+/// Starts mirroring the narinfo of an upstream hit into `location`
+pub fn spawn(
+    ctx: &Arc<ServerContext>,
+    location: &OciLocation,
+    auth: &RegistryAuth,
+    hit: &UpstreamHit,
+) {
+    if !ctx.options.upstream_mirror
+        || matches!(auth, RegistryAuth::Anonymous)
+        || !location.key.ends_with(".narinfo")
+    {
+        return;
+    }
+    if !ctx
+        .mirrors
+        .in_flight
+        .lock()
+        .unwrap()
+        .insert(location.clone())
+    {
+        return;
+    }
+    let ctx = ctx.clone();
+    let location = location.clone();
+    let auth = auth.clone();
+    let base = hit.upstream.url.clone();
+    let url = hit.url.clone();
+    let verified_text = hit.nar_info.clone();
+    tokio::spawn(async move {
+        match mirror(&ctx, &location, auth, &base, url, verified_text).await {
+            Ok(()) => (),
+            Err(e) => log::warn!("failed to mirror '{location}': {e}"),
+        }
+        ctx.mirrors.in_flight.lock().unwrap().remove(&location);
+    });
+}
+
+async fn mirror(
+    ctx: &ServerContext,
+    location: &OciLocation,
+    auth: RegistryAuth,
+    base: &Url,
+    url: Url,
+    verified_text: Option<String>,
+) -> Result<(), Error> {
+    let mut registry_ctx = ctx.write_context(location, auth)?;
+    if ctx.layer_info(&registry_ctx, location).await?.is_some() {
+        return Ok(());
+    }
+    // a verified narinfo is stored as is, fetching it again would skip the verification
+    let text = match verified_text {
+        Some(text) => text,
+        None => {
+            ctx.http_client
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?
+        }
+    };
+    let nar_info: NarInfo = text.parse()?;
+    check_nar_url(&nar_info)?;
+
+    let nar_location = OciLocation {
+        key: nar_info.url.clone(),
+        ..location.clone()
+    };
+    if ctx
+        .layer_info(&registry_ctx, &nar_location)
+        .await?
+        .is_none()
+    {
+        let nar_url = upstream_url(base, &nar_info.url)?;
+        log::info!("mirror: key = {}, url = {nar_url}", nar_location.key);
+        let response = ctx
+            .http_client
+            .get(nar_url)
+            .send()
+            .await?
+            .error_for_status()?;
+        registry::put_stream(
+            &mut registry_ctx,
+            &nar_location,
+            Some(NAR_CONTENT_TYPE.to_owned()),
+            verified(response.bytes_stream(), &nar_info),
+        )
+        .await?;
+        ctx.layer_infos.invalidate(&nar_location);
+    }
+
+    log::info!("mirror: key = {}", location.key);
+    let item = OciItem {
+        content_type: Some(NARINFO_CONTENT_TYPE.to_owned()),
+        data: text.into_bytes(),
+    };
+    registry::put(&mut registry_ctx, location, item).await?;
+    ctx.layer_infos.invalidate(location);
+    Ok(())
+}
+
+/// Checks that the NAR of a narinfo is at `nar/{hash}.nar[.{ext}]`, where `hash` is its `FileHash`
+fn check_nar_url(nar_info: &NarInfo) -> Result<(), Error> {
+    if !NAR_KEY_REGEX.is_match(&nar_info.url) {
+        return Err(Error::InvalidNarInfo(format!(
+            "unexpected URL '{}'",
+            nar_info.url
+        )));
+    }
+    if nar_info.file_hash.algorithm != "sha256" {
+        return Err(Error::InvalidNarInfo(format!(
+            "unsupported FileHash '{}'",
+            nar_info.file_hash
+        )));
+    }
+    let expected = nar_digest(&format!("nar/{}.nar", nar_info.file_hash.base32));
+    if nar_digest(&nar_info.url) != expected {
+        return Err(Error::InvalidNarInfo(format!(
+            "URL '{}' does not match FileHash '{}'",
+            nar_info.url, nar_info.file_hash
+        )));
+    }
+    Ok(())
+}
+
+/// Passes a NAR through, failing instead of ending if it does not match `FileHash` and `FileSize`
+///
+/// A failing stream aborts the upload, nothing is committed to the repository.
+fn verified<S>(
+    stream: S,
+    nar_info: &NarInfo,
+) -> impl Stream<Item = Result<bytes::Bytes, Error>> + use<S>
+where
+    S: Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Unpin,
+{
+    let expected_hash = nar_info.file_hash.base32.clone();
+    let expected_size = nar_info.file_size as u64;
+    futures::stream::unfold(
+        (stream, Some((Sha256::new(), 0u64))),
+        move |(mut stream, state)| {
+            let expected_hash = expected_hash.clone();
+            async move {
+                let (mut hasher, mut size) = state?;
+                let mismatch = match stream.next().await {
+                    Some(Ok(data)) => {
+                        hasher.update(&data);
+                        size += data.len() as u64;
+                        if size <= expected_size {
+                            return Some((Ok(data), (stream, Some((hasher, size)))));
+                        }
+                        format!("FileSize {expected_size}, got more")
+                    }
+                    Some(Err(e)) => return Some((Err(e.into()), (stream, None))),
+                    None if size != expected_size => {
+                        format!("FileSize {expected_size}, got {size}")
+                    }
+                    None => {
+                        let actual = to_nix_base32(&hasher.finalize()[..]);
+                        if actual == expected_hash {
+                            return None;
+                        }
+                        format!("FileHash sha256:{expected_hash}, got sha256:{actual}")
+                    }
+                };
+                Some((Err(Error::MirrorMismatch(mismatch)), (stream, None)))
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nar_info(data: &[u8]) -> NarInfo {
+        let hash = to_nix_base32(&Sha256::digest(data)[..]);
+        format!(
+            "StorePath: /nix/store/0000000000000000000000000000000a-hello\n\
+             URL: nar/{hash}.nar\n\
+             Compression: none\n\
+             FileHash: sha256:{hash}\n\
+             FileSize: {}\n\
+             NarHash: sha256:{hash}\n\
+             NarSize: {}\n\
+             References: \n",
+            data.len(),
+            data.len()
+        )
+        .parse()
+        .unwrap()
+    }
+
+    async fn collect(nar_info: &NarInfo, chunks: &[&'static [u8]]) -> Result<Vec<u8>, Error> {
+        let stream = futures::stream::iter(
+            chunks
+                .iter()
+                .map(|c| Ok::<_, reqwest::Error>(bytes::Bytes::from_static(c))),
+        );
+        let mut data = vec![];
+        let mut verified = Box::pin(verified(stream, nar_info));
+        while let Some(chunk) = verified.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data)
+    }
+
+    #[test]
+    fn nar_keys() {
+        let info = nar_info(b"nar");
+        assert!(NAR_KEY_REGEX.is_match(&info.url));
+        assert!(NAR_KEY_REGEX.is_match(&format!("{}.zst", info.url)));
+        assert!(!NAR_KEY_REGEX.is_match("nix-cache-info"));
+        assert!(!NAR_KEY_REGEX.is_match("0000000000000000000000000000000a.narinfo"));
+        assert!(!NAR_KEY_REGEX.is_match("nar/../nix-cache-info"));
+    }
+
+    #[test]
+    fn nar_url_matches_file_hash() {
+        let mut info = nar_info(b"nar");
+        assert!(check_nar_url(&info).is_ok());
+        info.url = nar_info(b"another nar").url;
+        assert!(matches!(
+            check_nar_url(&info),
+            Err(Error::InvalidNarInfo(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn verify_nar() {
+        let info = nar_info(b"hello world");
+        assert_eq!(
+            collect(&info, &[b"hello ", b"world"]).await.unwrap(),
+            b"hello world"
+        );
+        for chunks in [&[&b"hello there"[..]][..], &[b"hello"], &[b"hello world!"]] {
+            assert!(matches!(
+                collect(&info, chunks).await,
+                Err(Error::MirrorMismatch(_))
+            ));
+        }
+    }
+}
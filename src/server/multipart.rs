@@ -0,0 +1,374 @@
+//! S3 multipart uploads
+//!
+//! Each part is uploaded to the repository as an untagged blob,
+//! completing an upload concatenates the parts into the layer of a single layer image.
+//!
+//! Only clients with credentials can create uploads. The number of unfinished uploads,
+//! and the data of concurrent part uploads buffered in the server, are limited.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{
+        Mutex, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{body::Body, response::Response};
+use http::{StatusCode, header};
+use oci_client::secrets::RegistryAuth;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tokio::{sync::Semaphore, time};
+
+use crate::{
+    error::Error,
+    registry::{
+        self, OciLocation,
+        upload::{CHUNK_SIZE, UploadedBlob},
+    },
+    xml::{xml_escape, xml_unescape},
+};
+
+use super::{
+    ServerContext, auth,
+    s3::{XML_NAMESPACE, bucket, xml_response},
+};
+
+static PART_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new("(?s)<Part>(.*?)</Part>").unwrap());
+static PART_NUMBER_PATTERN: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"<PartNumber>\s*([0-9]+)\s*</PartNumber>").unwrap());
+static ETAG_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new("(?s)<ETag>(.*?)</ETag>").unwrap());
+
+const MAX_PART_NUMBER: u32 = 10000;
+/// Longest interval of purging expired uploads
+const MAX_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Multipart uploads in progress
+pub struct MultipartUploads {
+    timeout: Duration,
+    max_uploads: usize,
+    next_id: AtomicU64,
+    uploads: Mutex<HashMap<String, MultipartUpload>>,
+    /// One permit per chunk buffered by a part upload
+    buffers: Semaphore,
+}
+
+#[derive(Debug, Clone)]
+struct MultipartUpload {
+    location: OciLocation,
+    /// Fingerprint of the credentials creating the upload
+    credentials: String,
+    content_type: Option<String>,
+    parts: BTreeMap<u32, UploadedBlob>,
+    created: Instant,
+}
+
+impl MultipartUploads {
+    pub fn new(timeout: Duration, max_uploads: usize, max_buffer: usize) -> Self {
+        Self {
+            timeout,
+            max_uploads,
+            next_id: AtomicU64::new(0),
+            uploads: Mutex::new(HashMap::new()),
+            buffers: Semaphore::new((max_buffer / CHUNK_SIZE).max(1)),
+        }
+    }
+
+    fn create(
+        &self,
+        location: OciLocation,
+        credentials: String,
+        content_type: Option<String>,
+    ) -> Result<String, Error> {
+        let now = Instant::now();
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let id = format!(
+            "{nanos:016x}{:016x}",
+            self.next_id.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut uploads = self.uploads.lock().unwrap();
+        if uploads.len() >= self.max_uploads {
+            return Err(Error::MultipartUploadLimit("uploads"));
+        }
+        uploads.insert(
+            id.clone(),
+            MultipartUpload {
+                location,
+                credentials,
+                content_type,
+                parts: BTreeMap::new(),
+                created: now,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Drops abandoned uploads, their parts are left to the garbage collection of the registry
+    fn purge(&self) {
+        let mut uploads = self.uploads.lock().unwrap();
+        let before = uploads.len();
+        uploads.retain(|_, u| u.created.elapsed() < self.timeout);
+        if uploads.len() < before {
+            log::debug!(
+                "dropped {} expired multipart uploads",
+                before - uploads.len()
+            );
+        }
+    }
+
+    /// Upload `id` of `location`, only visible to the credentials creating it
+    fn get(
+        &self,
+        id: &str,
+        location: &OciLocation,
+        credentials: &str,
+    ) -> Result<MultipartUpload, Error> {
+        let uploads = self.uploads.lock().unwrap();
+        match uploads.get(id) {
+            Some(u)
+                if &u.location == location
+                    && u.credentials == credentials
+                    && u.created.elapsed() < self.timeout =>
+            {
+                Ok(u.clone())
+            }
+            _ => Err(Error::NoSuchUpload(id.to_owned())),
+        }
+    }
+
+    fn add_part(&self, id: &str, number: u32, blob: UploadedBlob) -> Result<(), Error> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let upload = uploads
+            .get_mut(id)
+            .ok_or_else(|| Error::NoSuchUpload(id.to_owned()))?;
+        upload.parts.insert(number, blob);
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) {
+        self.uploads.lock().unwrap().remove(id);
+    }
+}
+
+impl fmt::Debug for MultipartUploads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let uploads = self.uploads.lock().unwrap();
+        f.debug_struct("MultipartUploads")
+            .field("timeout", &self.timeout)
+            .field("uploads", &uploads.len())
+            .finish()
+    }
+}
+
+/// Purges expired uploads periodically, until the server is dropped
+pub async fn purge_expired(ctx: Weak<ServerContext>) {
+    let interval = match ctx.upgrade() {
+        Some(ctx) => ctx.multipart_uploads.timeout.min(MAX_PURGE_INTERVAL),
+        None => return,
+    };
+    let mut interval = time::interval(interval);
+    loop {
+        interval.tick().await;
+        match ctx.upgrade() {
+            Some(ctx) => ctx.multipart_uploads.purge(),
+            None => return,
+        }
+    }
+}
+
+/// CreateMultipartUpload
+pub fn create(
+    ctx: &ServerContext,
+    location: OciLocation,
+    auth: &RegistryAuth,
+    content_type: Option<String>,
+) -> Result<Response<Body>, Error> {
+    // uploads are kept in memory, anonymous clients could create them without limit
+    if let RegistryAuth::Anonymous = auth {
+        return Err(Error::AnonymousWrite(location));
+    }
+    let bucket = bucket(&location).to_owned();
+    let key = location.key.clone();
+    let id = ctx
+        .multipart_uploads
+        .create(location, auth::fingerprint(auth), content_type)?;
+    log::debug!("create multipart upload: {id}");
+    xml_response(format!(
+        "<InitiateMultipartUploadResult xmlns=\"{XML_NAMESPACE}\">\
+         <Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId>\
+         </InitiateMultipartUploadResult>",
+        xml_escape(&bucket),
+        xml_escape(&key),
+        xml_escape(&id),
+    ))
+}
+
+/// UploadPart
+pub async fn upload_part(
+    ctx: &ServerContext,
+    location: OciLocation,
+    auth: RegistryAuth,
+    id: &str,
+    part_number: &str,
+    body: Body,
+) -> Result<Response<Body>, Error> {
+    let number = parse_part_number(part_number)?;
+    ctx.multipart_uploads
+        .get(id, &location, &auth::fingerprint(&auth))?;
+    let registry_ctx = ctx.write_context(&location, auth)?;
+    let _buffer = ctx
+        .multipart_uploads
+        .buffers
+        .try_acquire()
+        .map_err(|_| Error::MultipartUploadLimit("buffered parts"))?;
+    let blob = registry::upload_stream(&registry_ctx, &location, body.into_data_stream()).await?;
+    log::debug!("upload part {number} of {id}: {}", blob.digest);
+    let etag = etag(&blob);
+    ctx.multipart_uploads.add_part(id, number, blob)?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::ETAG, format!("\"{etag}\""))
+        .body(Body::empty())
+        .map_err(Error::Http)
+}
+
+/// CompleteMultipartUpload
+pub async fn complete(
+    ctx: &ServerContext,
+    location: OciLocation,
+    auth: RegistryAuth,
+    id: &str,
+    body: &[u8],
+) -> Result<Response<Body>, Error> {
+    let upload = ctx
+        .multipart_uploads
+        .get(id, &location, &auth::fingerprint(&auth))?;
+    let requested = parse_complete_request(body)?;
+    let mut parts = vec![];
+    let mut last = 0;
+    for (number, requested_etag) in requested {
+        if number <= last {
+            return Err(Error::InvalidPartOrder);
+        }
+        last = number;
+        match upload.parts.get(&number) {
+            Some(blob) if etag(blob) == requested_etag => parts.push(blob.clone()),
+            _ => return Err(Error::InvalidPart(number)),
+        }
+    }
+
+    let mut registry_ctx = ctx.write_context(&location, auth)?;
+    let blob = match parts.as_slice() {
+        // the only part is already the layer
+        [part] => part.clone(),
+        _ => registry::upload_concat(&registry_ctx, &location, &parts).await?,
+    };
+    let etag = format!("{}-{}", etag(&blob), parts.len());
+    registry::put_uploaded(&mut registry_ctx, &location, upload.content_type, blob).await?;
+    ctx.layer_infos.invalidate(&location);
+    ctx.multipart_uploads.remove(id);
+    log::debug!("complete multipart upload: {id}");
+
+    xml_response(format!(
+        "<CompleteMultipartUploadResult xmlns=\"{XML_NAMESPACE}\">\
+         <Bucket>{}</Bucket><Key>{}</Key><ETag>&quot;{etag}&quot;</ETag>\
+         </CompleteMultipartUploadResult>",
+        xml_escape(bucket(&location)),
+        xml_escape(&location.key),
+    ))
+}
+
+/// AbortMultipartUpload
+pub fn abort(
+    ctx: &ServerContext,
+    location: OciLocation,
+    auth: &RegistryAuth,
+    id: &str,
+) -> Result<Response<Body>, Error> {
+    ctx.multipart_uploads
+        .get(id, &location, &auth::fingerprint(auth))?;
+    ctx.multipart_uploads.remove(id);
+    log::debug!("abort multipart upload: {id}");
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .map_err(Error::Http)
+}
+
+fn etag(blob: &UploadedBlob) -> String {
+    blob.digest.trim_start_matches("sha256:").to_owned()
+}
+
+fn parse_part_number(s: &str) -> Result<u32, Error> {
+    match s.parse() {
+        Ok(n) if (1..=MAX_PART_NUMBER).contains(&n) => Ok(n),
+        _ => Err(Error::InvalidPartNumber(s.to_owned())),
+    }
+}
+
+/// Parses part numbers and etags in a CompleteMultipartUpload request
+fn parse_complete_request(body: &[u8]) -> Result<Vec<(u32, String)>, Error> {
+    let body = String::from_utf8_lossy(body);
+    let malformed = |msg: &str| Error::MalformedXml(msg.to_owned());
+    let mut parts = vec![];
+    for part in PART_PATTERN.captures_iter(&body) {
+        let part = &part[1];
+        let number = PART_NUMBER_PATTERN
+            .captures(part)
+            .ok_or_else(|| malformed("part without part number"))?;
+        let number = parse_part_number(&number[1])?;
+        let etag = ETAG_PATTERN
+            .captures(part)
+            .ok_or_else(|| malformed("part without etag"))?;
+        let etag = xml_unescape(&etag[1]).trim().trim_matches('"').to_owned();
+        parts.push((number, etag));
+    }
+    if parts.is_empty() {
+        return Err(malformed("no parts"));
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limit_and_purge_uploads() {
+        let location = OciLocation {
+            registry: "ghcr.io".to_owned(),
+            repository: "owner/cache".to_owned(),
+            key: "nar/a.nar".to_owned(),
+        };
+        let uploads = MultipartUploads::new(Duration::ZERO, 1, CHUNK_SIZE);
+        let create = || uploads.create(location.clone(), "credentials".to_owned(), None);
+        assert!(create().is_ok());
+        assert!(matches!(create(), Err(Error::MultipartUploadLimit(_))));
+        uploads.purge();
+        assert!(create().is_ok());
+    }
+
+    #[test]
+    fn test_parse_complete_request() {
+        let body = br#"<?xml version="1.0" encoding="UTF-8"?>
+<CompleteMultipartUpload xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Part><ETag>&quot;aa&quot;</ETag><PartNumber>1</PartNumber></Part>
+  <Part><PartNumber>2</PartNumber><ETag>"bb"</ETag></Part>
+</CompleteMultipartUpload>"#;
+        assert_eq!(
+            parse_complete_request(body).unwrap(),
+            vec![(1, "aa".to_owned()), (2, "bb".to_owned())]
+        );
+        assert!(parse_complete_request(b"<CompleteMultipartUpload/>").is_err());
+        assert!(
+            parse_complete_request(b"<Part><PartNumber>0</PartNumber><ETag>a</ETag></Part>")
+                .is_err()
+        );
+    }
+}
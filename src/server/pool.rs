@@ -0,0 +1,118 @@
+use std::{
+    fmt,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use oci_client::{Client, secrets::RegistryAuth};
+
+use crate::registry::{PullTokens, RegistryOptions};
+
+use super::auth::fingerprint;
+
+/// Pool of OCI clients shared across requests
+///
+/// An `oci_client::Client` binds the first credentials it sees to a registry,
+/// and caches bearer tokens and connections internally,
+/// so one client is kept for each (registry, credentials) pair,
+/// along with tokens of pulls sent without it.
+/// The least recently used clients are dropped beyond the capacity,
+/// since clients may send any number of distinct credentials.
+pub struct ClientPool {
+    options: RegistryOptions,
+    idle_timeout: Duration,
+    clients: Mutex<LruCache<ClientKey, PooledClient>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ClientKey {
+    registry: String,
+    credentials: String,
+}
+
+struct PooledClient {
+    client: Client,
+    pull_tokens: PullTokens,
+    last_used: Instant,
+}
+
+impl ClientPool {
+    pub fn new(options: RegistryOptions, idle_timeout: Duration, capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            options,
+            idle_timeout,
+            clients: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    pub fn get(&self, registry: &str, auth: &RegistryAuth) -> (Client, PullTokens) {
+        let key = ClientKey {
+            registry: registry.to_owned(),
+            credentials: fingerprint(auth),
+        };
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        // drop idle clients, their tokens are likely expired anyway,
+        // clients are ordered by last use so idle ones are at the end
+        while clients
+            .peek_lru()
+            .is_some_and(|(_, c)| now.duration_since(c.last_used) >= self.idle_timeout)
+        {
+            clients.pop_lru();
+        }
+        let pooled = clients.get_or_insert_mut(key, || {
+            log::debug!("create oci client for registry '{registry}'");
+            PooledClient {
+                client: self.options.client(),
+                pull_tokens: PullTokens::default(),
+                last_used: now,
+            }
+        });
+        pooled.last_used = now;
+        (pooled.client.clone(), pooled.pull_tokens.clone())
+    }
+}
+
+impl fmt::Debug for ClientPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientPool")
+            .field("options", &self.options)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::convert::{EncodingOptions, TagEncoding};
+
+    use super::*;
+
+    #[test]
+    fn bounded_pool() {
+        let options = RegistryOptions {
+            no_ssl: false,
+            dry_run: false,
+            max_retry: 3,
+            encoding_options: EncodingOptions {
+                tag_encoding: TagEncoding::Custom,
+                fallback_encodings: vec![],
+            },
+        };
+        let pool = ClientPool::new(options, Duration::from_secs(600), 2);
+        let auth = |n: usize| RegistryAuth::Basic(format!("user-{n}"), "password".to_owned());
+        for n in 0..3 {
+            pool.get("ghcr.io", &auth(n));
+        }
+        let clients = pool.clients.lock().unwrap();
+        assert_eq!(clients.len(), 2);
+        // the least recently used client is dropped
+        assert!(!clients.contains(&ClientKey {
+            registry: "ghcr.io".to_owned(),
+            credentials: fingerprint(&auth(0)),
+        }));
+    }
+}
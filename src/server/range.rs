@@ -0,0 +1,143 @@
+//! Single range `Range` requests
+//!
+//! Multiple ranges and ill-formed headers are ignored, and the full content is served,
+//! as allowed by RFC 9110.
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt, future};
+use http::HeaderValue;
+
+/// Inclusive byte range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeRequest {
+    Full,
+    Partial(ByteRange),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Value of the `Content-Range` header
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{size}", self.start, self.end)
+    }
+}
+
+/// Parses the `Range` header for content of `size` bytes
+pub fn parse(header: Option<&HeaderValue>, size: u64) -> RangeRequest {
+    let spec = match header
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.trim().strip_prefix("bytes="))
+    {
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return RangeRequest::Full,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(pair) => pair,
+        None => return RangeRequest::Full,
+    };
+    let parse_pos = |s: &str| s.parse::<u64>().ok();
+    match (first, last) {
+        // suffix range, the last n bytes
+        ("", n) => match parse_pos(n) {
+            Some(0) => RangeRequest::Unsatisfiable,
+            Some(_) if size == 0 => RangeRequest::Unsatisfiable,
+            Some(n) => RangeRequest::Partial(ByteRange {
+                start: size.saturating_sub(n),
+                end: size - 1,
+            }),
+            None => RangeRequest::Full,
+        },
+        (start, "") => match parse_pos(start) {
+            Some(start) if start >= size => RangeRequest::Unsatisfiable,
+            Some(start) => RangeRequest::Partial(ByteRange {
+                start,
+                end: size - 1,
+            }),
+            None => RangeRequest::Full,
+        },
+        (start, end) => match (parse_pos(start), parse_pos(end)) {
+            (Some(start), Some(end)) if start > end => RangeRequest::Full,
+            (Some(start), Some(_)) if start >= size => RangeRequest::Unsatisfiable,
+            (Some(start), Some(end)) => RangeRequest::Partial(ByteRange {
+                start,
+                end: end.min(size - 1),
+            }),
+            _ => RangeRequest::Full,
+        },
+    }
+}
+
+/// Cuts `range` out of a stream of the full content,
+/// for registries ignoring range requests
+pub fn slice<S, E>(stream: S, range: ByteRange) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    stream.scan(0u64, move |position, item| {
+        let result = match item {
+            Err(e) => Some(Err(e)),
+            Ok(_) if *position > range.end => None,
+            Ok(data) => {
+                let data_start = *position;
+                let data_len = data.len() as u64;
+                *position += data_len;
+                let from = range.start.saturating_sub(data_start).min(data_len);
+                let to = (range.end + 1 - data_start).min(data_len);
+                Some(Ok(data.slice(from as usize..to as usize)))
+            }
+        };
+        future::ready(result)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{TryStreamExt, stream};
+
+    use super::*;
+
+    fn parse_str(s: &str, size: u64) -> RangeRequest {
+        parse(Some(&HeaderValue::from_str(s).unwrap()), size)
+    }
+
+    fn partial(start: u64, end: u64) -> RangeRequest {
+        RangeRequest::Partial(ByteRange { start, end })
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(None, 10), RangeRequest::Full);
+        assert_eq!(parse_str("bytes=0-4", 10), partial(0, 4));
+        assert_eq!(parse_str("bytes=5-", 10), partial(5, 9));
+        assert_eq!(parse_str("bytes=-3", 10), partial(7, 9));
+        assert_eq!(parse_str("bytes=-30", 10), partial(0, 9));
+        assert_eq!(parse_str("bytes=8-100", 10), partial(8, 9));
+        assert_eq!(parse_str("bytes=10-", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_str("bytes=-0", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_str("bytes=0-1,3-4", 10), RangeRequest::Full);
+        assert_eq!(parse_str("bytes=4-3", 10), RangeRequest::Full);
+        assert_eq!(parse_str("items=0-1", 10), RangeRequest::Full);
+    }
+
+    #[tokio::test]
+    async fn test_slice() {
+        let chunks = ["abc", "def", "ghi"]
+            .into_iter()
+            .map(|s| Ok::<_, std::io::Error>(Bytes::from_static(s.as_bytes())));
+        let sliced: Vec<Bytes> = slice(stream::iter(chunks), ByteRange { start: 2, end: 6 })
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(sliced.concat(), b"cdefg");
+    }
+}
@@ -0,0 +1,165 @@
+//! Re-signing narinfos served from the repository with the server signing key
+//!
+//! Narinfos not parsable or having a conflicting signature of the same key name are served as is.
+
+use std::{env, fs, path::Path};
+
+use axum::{body::Body, response::Response};
+use bytes::Bytes;
+use data_encoding::HEXLOWER;
+use futures::TryStreamExt;
+use http::{HeaderMap, StatusCode, header};
+
+use crate::{
+    error::Error,
+    nix::{NarInfo, sign::NixKeyPair},
+    registry::{self, LayerInfo, OciLocation, RegistryContext},
+};
+
+use super::check_preconditions;
+
+pub const SIGNING_KEY_ENV: &str = "ORANC_SERVER_SIGNING_KEY";
+
+/// Loads the signing key from `file`, or from `ORANC_SERVER_SIGNING_KEY`
+pub fn load_key(file: Option<&Path>) -> Result<Option<NixKeyPair>, Error> {
+    let content = match file {
+        Some(path) => fs::read_to_string(path)?,
+        None => match env::var(SIGNING_KEY_ENV) {
+            Ok(content) => content,
+            Err(env::VarError::NotPresent) => return Ok(None),
+            Err(e) => return Err(Error::InvalidSigningKeyEnv(e)),
+        },
+    };
+    let key_pair = NixKeyPair::from_secret_key_str(content.trim())?;
+    log::info!("re-sign narinfos with key '{}'", key_pair.name);
+    Ok(Some(key_pair))
+}
+
+/// Entity tag of a re-signed narinfo, which changes with the layer and the signing key
+///
+/// The public key is included since a rotated key may keep its name.
+pub fn etag(info: &LayerInfo, key_pair: &NixKeyPair) -> String {
+    format!(
+        "\"{}+{}:{}\"",
+        info.digest,
+        key_pair.name,
+        HEXLOWER.encode(key_pair.key_pair.pk.as_ref())
+    )
+}
+
+/// Response with the re-signed narinfo, range requests are ignored
+pub async fn response(
+    registry_ctx: &RegistryContext,
+    headers: &HeaderMap,
+    location: &OciLocation,
+    info: &LayerInfo,
+    key_pair: &NixKeyPair,
+    with_body: bool,
+) -> Result<Response<Body>, Error> {
+    let etag = etag(info, key_pair);
+    if let Some(response) = check_preconditions(headers, &etag, location)? {
+        return Ok(response);
+    }
+    let chunks: Vec<Bytes> =
+        registry::pull_blob_stream(registry_ctx, &info.reference, &info.digest)
+            .await?
+            .try_collect()
+            .await?;
+    let original =
+        String::from_utf8(chunks.concat()).map_err(|e| Error::InvalidNarInfo(e.to_string()))?;
+    let document = match resign(&original, key_pair) {
+        Ok(document) => document,
+        Err(e) => {
+            log::warn!("serve '{location}' without re-signing: {e}");
+            original
+        }
+    };
+    let body = if with_body {
+        Body::from(document.clone())
+    } else {
+        Body::empty()
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, &info.content_type)
+        .header(header::ETAG, etag)
+        .header(header::CONTENT_LENGTH, document.len())
+        .body(body)
+        .map_err(Error::Http)
+}
+
+/// Adds the signature of `key_pair` to the narinfo document
+///
+/// Lines other than signatures are kept verbatim, including fields `NarInfo` does not know.
+pub fn resign(document: &str, key_pair: &NixKeyPair) -> Result<String, Error> {
+    let mut nar_info: NarInfo = document.parse()?;
+    let fingerprint = nar_info.fingerprint()?;
+    let signature = key_pair.sign(fingerprint.as_bytes())?;
+    nar_info
+        .sigs
+        .merge(key_pair, fingerprint.as_bytes(), signature)?;
+    let mut resigned = String::with_capacity(document.len());
+    for line in document.lines() {
+        if !line.is_empty() && !line.starts_with("Sig: ") {
+            resigned.push_str(line);
+            resigned.push('\n');
+        }
+    }
+    for sig in &nar_info.sigs.0 {
+        resigned.push_str(&format!("Sig: {sig}\n"));
+    }
+    Ok(resigned)
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_compact::{KeyPair, Seed};
+
+    use super::*;
+
+    #[test]
+    fn test_resign() {
+        let key_pair = NixKeyPair {
+            name: "new-1".to_owned(),
+            key_pair: KeyPair::from_seed(Seed::new([2; 32])),
+        };
+        let document = "StorePath: /nix/store/0000000000000000000000000000000a-hello\n\
+                        URL: nar/0000000000000000000000000000000000000000000000000000.nar.zst\n\
+                        Compression: zstd\n\
+                        FileHash: sha256:0000000000000000000000000000000000000000000000000000\n\
+                        FileSize: 10\n\
+                        NarHash: sha256:1111111111111111111111111111111111111111111111111111\n\
+                        NarSize: 20\n\
+                        References: \n\
+                        Sig: old-1:c2lnbmF0dXJl\n\
+                        System: x86_64-linux\n";
+        let text = resign(document, &key_pair).unwrap();
+        assert!(text.contains("System: x86_64-linux\n"));
+        let resigned: NarInfo = text.parse().unwrap();
+        assert_eq!(resigned.sigs.0.len(), 2);
+        let fingerprint = resigned.fingerprint().unwrap();
+        key_pair
+            .verify(fingerprint.as_bytes(), &resigned.sigs.0[1])
+            .unwrap();
+        // signing again keeps one signature of the key
+        let again = resign(&text, &key_pair).unwrap();
+        assert_eq!(again, text);
+    }
+
+    #[test]
+    fn etag_changes_with_layer_and_key() {
+        let key_pair = |seed| NixKeyPair {
+            name: "key-1".to_owned(),
+            key_pair: KeyPair::from_seed(Seed::new([seed; 32])),
+        };
+        let info = |digest: &str| LayerInfo {
+            reference: "registry.example/repo:tag".parse().unwrap(),
+            digest: digest.to_owned(),
+            size: 10,
+            content_type: "text/x-nix-narinfo".to_owned(),
+        };
+        let etag_a = etag(&info("sha256:aa"), &key_pair(1));
+        assert_ne!(etag_a, etag(&info("sha256:bb"), &key_pair(1)));
+        assert_ne!(etag_a, etag(&info("sha256:aa"), &key_pair(2)));
+    }
+}
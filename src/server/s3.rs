@@ -0,0 +1,26 @@
+use axum::{body::Body, response::Response};
+use http::{StatusCode, header};
+
+use crate::{error::Error, registry::OciLocation};
+
+pub const XML_DECLARATION: &str = r#"<?xml version="1.0" encoding="UTF-8"?>"#;
+pub const XML_NAMESPACE: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// Response with an s3 xml document
+pub fn xml_response(document: String) -> Result<Response<Body>, Error> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/xml")
+        .body(format!("{XML_DECLARATION}{document}").into())
+        .map_err(Error::Http)
+}
+
+/// Bucket seen by s3 clients
+///
+/// Clients use `s3://{last part of repository}?endpoint={server}/{registry}/{other parts of repository}`.
+pub fn bucket(location: &OciLocation) -> &str {
+    match location.repository.rsplit_once('/') {
+        Some((_, bucket)) => bucket,
+        None => &location.repository,
+    }
+}
@@ -0,0 +1,121 @@
+//! Graceful shutdown on SIGTERM and SIGINT
+//!
+//! After a signal, new connections are no longer accepted, `/readyz` fails,
+//! and in-flight requests are drained until they finish or the deadline passes.
+//! A second signal exits at once.
+
+use std::{
+    future::IntoFuture,
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::watch,
+    time,
+};
+
+use crate::error::Error;
+
+use super::ServerContext;
+
+static IN_FLIGHT_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+/// Counts a request as in flight until dropped
+#[derive(Debug)]
+pub struct RequestGuard(());
+
+impl RequestGuard {
+    pub fn enter() -> Self {
+        IN_FLIGHT_REQUESTS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT_REQUESTS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Shutdown {
+    /// Starts listening to SIGTERM and SIGINT
+    pub fn listen() -> Result<Self, Error> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let (sender, receiver) = watch::channel(false);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = terminate.recv() => log::info!("received SIGTERM"),
+                    _ = interrupt.recv() => log::info!("received SIGINT"),
+                }
+                if *sender.borrow() {
+                    log::warn!("received a second signal, exiting immediately");
+                    std::process::exit(1);
+                }
+                let _ = sender.send(true);
+            }
+        });
+        Ok(Self(receiver))
+    }
+
+    /// Whether a signal is received
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once a signal is received
+    pub async fn triggered(mut self) {
+        let _ = self.0.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// Runs a gracefully shutting down server, giving up on in-flight requests after `deadline`
+pub async fn serve_until<F>(
+    serve: F,
+    ctx: &ServerContext,
+    shutdown: Shutdown,
+    deadline: Duration,
+) -> Result<(), Error>
+where
+    F: IntoFuture<Output = io::Result<()>>,
+{
+    let deadline_passed = async {
+        shutdown.triggered().await;
+        log::info!(
+            "shutting down, draining in-flight requests for up to {} seconds",
+            deadline.as_secs()
+        );
+        time::sleep(deadline).await;
+    };
+    tokio::select! {
+        result = serve.into_future() => {
+            log_abandoned(ctx);
+            log::info!("shut down");
+            Ok(result?)
+        }
+        _ = deadline_passed => {
+            log::warn!("in-flight requests not finished before the deadline, exiting");
+            log_abandoned(ctx);
+            Ok(())
+        }
+    }
+}
+
+/// Logs requests and background tasks dropped on exit
+fn log_abandoned(ctx: &ServerContext) {
+    let requests = IN_FLIGHT_REQUESTS.load(Ordering::Relaxed);
+    let mirrors = ctx.mirrors.in_flight();
+    let fills = ctx.blobs.as_ref().map_or(0, |blobs| blobs.filling());
+    if requests + mirrors + fills != 0 {
+        log::warn!(
+            "abandoned {requests} requests, {mirrors} mirror tasks and {fills} blob cache fills"
+        );
+    }
+}
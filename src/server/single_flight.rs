@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    hash::Hash,
+    panic,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use futures::{
+    FutureExt,
+    future::{BoxFuture, Shared},
+};
+
+/// Deduplicates concurrent calls with the same key
+///
+/// While a call for a key is in flight, later callers with the same key
+/// wait for it and receive a clone of its result instead of starting their own.
+/// Calls run in their own tasks, so they finish and are forgotten even if every caller is cancelled.
+pub struct SingleFlight<K, V> {
+    calls: Arc<Mutex<HashMap<K, Call<V>>>>,
+    next_id: AtomicU64,
+}
+
+struct Call<V> {
+    id: u64,
+    result: Shared<BoxFuture<'static, V>>,
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self {
+            calls: Arc::new(Mutex::new(HashMap::new())),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V> + Send + 'static,
+    {
+        let result = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(&key) {
+                Some(call) => call.result.clone(),
+                None => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    let future = f();
+                    let task_calls = self.calls.clone();
+                    let task_key = key.clone();
+                    // the task waits for the lock held here, so the call is inserted before removed
+                    let task = tokio::spawn(async move {
+                        let value = future.await;
+                        let mut calls = task_calls.lock().unwrap();
+                        if calls.get(&task_key).is_some_and(|c| c.id == id) {
+                            calls.remove(&task_key);
+                        }
+                        value
+                    });
+                    let result = async move {
+                        match task.await {
+                            Ok(value) => value,
+                            Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+                            Err(e) => panic!("single flight call cancelled: {e}"),
+                        }
+                    }
+                    .boxed()
+                    .shared();
+                    calls.insert(
+                        key,
+                        Call {
+                            id,
+                            result: result.clone(),
+                        },
+                    );
+                    result
+                }
+            }
+        };
+        result.await
+    }
+}
+
+impl<K, V> Default for SingleFlight<K, V>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> fmt::Debug for SingleFlight<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SingleFlight").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use tokio::sync::Notify;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn share_concurrent_calls() {
+        let flight = SingleFlight::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Notify::new());
+        let call = || {
+            let calls = calls.clone();
+            let release = release.clone();
+            async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                release.notified().await;
+                42
+            }
+        };
+        let (a, b, ()) = tokio::join!(flight.run("key", call), flight.run("key", call), async {
+            tokio::task::yield_now().await;
+            release.notify_one();
+        });
+        assert_eq!((a, b), (42, 42));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // finished calls are forgotten
+        assert!(flight.calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn finish_cancelled_calls() {
+        let flight = SingleFlight::new();
+        let release = Arc::new(Notify::new());
+        let waiting = release.clone();
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(10),
+            flight.run("key", || async move {
+                waiting.notified().await;
+                1
+            }),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        release.notify_one();
+        while !flight.calls.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(flight.run("key", || async { 2 }).await, 2);
+    }
+}
@@ -1,28 +1,84 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use axum::{body::Body, response::Response};
-use http::StatusCode;
+use data_encoding::HEXLOWER;
+use futures::stream::FuturesOrdered;
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header};
 use oci_client::secrets::RegistryAuth;
 use reqwest::Url;
+use tokio::time;
 
 use crate::error::Error;
+use crate::metrics::METRICS;
+use crate::nix::sign::NixPublicKey;
+use crate::nix::{NAR_CONTENT_TYPE, NARINFO_CONTENT_TYPE, NarInfo};
+use crate::options::{Upstream, UpstreamMode};
+use crate::registry::OciLocation;
 
 use super::ServerContext;
+use super::access_log;
+use super::blob_flights::{self, Fetch};
+use super::mirror;
 
-pub async fn check_and_redirect(
-    ctx: &ServerContext,
-    key: &str,
+/// Response headers passed through from proxied upstreams
+const FORWARDED_HEADERS: [HeaderName; 7] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_ENCODING,
+    header::CONTENT_RANGE,
+    header::ACCEPT_RANGES,
+    header::ETAG,
+    header::LAST_MODIFIED,
+];
+
+/// An upstream having the key
+pub struct UpstreamHit<'a> {
+    pub upstream: &'a Upstream,
+    pub url: Url,
+    /// Headers of the probing response
+    pub headers: HeaderMap,
+    /// Narinfo verified against `--upstream-trusted-public-key`,
+    /// served and mirrored as is instead of being fetched again
+    pub nar_info: Option<String>,
+}
+
+pub async fn check_and_respond(
+    ctx: &Arc<ServerContext>,
+    location: &OciLocation,
     auth: &RegistryAuth,
+    request_headers: &HeaderMap,
+    with_body: bool,
 ) -> Result<Option<Response<Body>>, Error> {
-    match check(ctx, key, auth).await? {
-        Some(url) => Ok(Some(redirect_response(key, &url)?)),
-        None => Ok(None),
-    }
+    let key = &location.key;
+    let hit = match check(ctx, key, auth).await? {
+        Some(hit) => hit,
+        None => return Ok(None),
+    };
+    access_log::record(|entry| {
+        entry.upstream = Some(match hit.upstream.mode {
+            UpstreamMode::Redirect => "redirect",
+            UpstreamMode::Proxy => "proxy",
+        })
+    });
+    mirror::spawn(ctx, location, auth, &hit);
+    let response = match (&hit.nar_info, hit.upstream.mode) {
+        (Some(text), _) => nar_info_response(key, &hit.url, text, with_body)?,
+        (None, UpstreamMode::Redirect) => redirect_response(key, &hit.url)?,
+        (None, UpstreamMode::Proxy) => {
+            proxy_response(ctx, key, hit, request_headers, with_body).await?
+        }
+    };
+    Ok(Some(response))
 }
 
-pub async fn check(
-    ctx: &ServerContext,
+/// Probes all upstreams concurrently, returning the hit with the highest priority
+pub async fn check<'a>(
+    ctx: &'a ServerContext,
     key: &str,
     auth: &RegistryAuth,
-) -> Result<Option<Url>, Error> {
+) -> Result<Option<UpstreamHit<'a>>, Error> {
     let max_retry = ctx.options.max_retry;
     if max_retry < 1 {
         return Err(Error::InvalidMaxRetry(max_retry));
@@ -32,29 +88,138 @@ pub async fn check(
         // skip check upstream caches if `--upstream-anonymous` is off
         if !ctx.options.upstream_anonymous {
             log::debug!("skipped checking upstream for key: '{}'", key);
+            access_log::record(|entry| entry.upstream = Some("skipped"));
             return Ok(None);
         }
     }
-    if ctx.options.ignore_upstream.is_match(key) {
+    if ctx.options.ignore_upstream.is_match(key) || ctx.options.upstream.is_empty() {
+        access_log::record(|entry| entry.upstream = Some("skipped"));
+        return Ok(None);
+    }
+    if ctx.upstream_misses.contains(key) {
+        log::debug!("upstream miss cache hit: '{key}'");
+        METRICS
+            .upstream_checks
+            .with_label_values(&["cached_miss"])
+            .inc();
+        access_log::record(|entry| entry.upstream = Some("cached_miss"));
         return Ok(None);
     }
-    for upstream in &ctx.options.upstream {
-        let url = upstream_url(upstream, key)?;
-        for attempt in 1..max_retry {
-            let response = ctx.http_client.head(url.clone()).send().await?;
-            if response.status() == StatusCode::OK {
-                return Ok(Some(url));
-            } else if response.status() == StatusCode::NOT_FOUND {
-                break;
-            } else {
+
+    let mut upstreams: Vec<_> = ctx.options.upstream.iter().enumerate().collect();
+    upstreams.sort_by_key(|(index, upstream)| (upstream.priority, *index));
+    let mut probes = FuturesOrdered::new();
+    for (_, upstream) in upstreams {
+        let url = upstream_url(&upstream.url, key)?;
+        probes.push_back(async move {
+            let probe = probe(ctx, key, &url);
+            let result = match time::timeout(upstream_timeout(ctx, upstream), probe).await {
+                Ok(result) => result,
+                Err(_) => {
+                    log::warn!("query upstream url '{url}' timed out");
+                    Probe::Failed
+                }
+            };
+            (upstream, url, result)
+        });
+    }
+    // results arrive in the order of priority, remaining probes are dropped on the first hit
+    let mut all_missed = true;
+    while let Some((upstream, url, result)) = probes.next().await {
+        match result {
+            Probe::Hit(headers, nar_info) => {
+                METRICS.upstream_checks.with_label_values(&["hit"]).inc();
+                return Ok(Some(UpstreamHit {
+                    upstream,
+                    url,
+                    headers,
+                    nar_info,
+                }));
+            }
+            Probe::Miss => (),
+            Probe::Failed => all_missed = false,
+        }
+    }
+    // failures are not cached, the key may be found once upstreams recover
+    if all_missed {
+        METRICS.upstream_checks.with_label_values(&["miss"]).inc();
+        access_log::record(|entry| entry.upstream = Some("miss"));
+        ctx.upstream_misses.insert(key.to_owned());
+    } else {
+        METRICS.upstream_checks.with_label_values(&["error"]).inc();
+        access_log::record(|entry| entry.upstream = Some("error"));
+    }
+    Ok(None)
+}
+
+enum Probe {
+    /// Headers, and the verified narinfo if signatures are checked
+    Hit(HeaderMap, Option<String>),
+    Miss,
+    Failed,
+}
+
+async fn probe(ctx: &ServerContext, key: &str, url: &Url) -> Probe {
+    let max_retry = ctx.options.max_retry;
+    let trusted_keys = &ctx.options.upstream_trusted_public_key;
+    // narinfos are fetched to verify their signatures,
+    // NARs are verified by clients against the file hash in the narinfo
+    let verify = !trusted_keys.is_empty() && key.ends_with(".narinfo");
+    for attempt in 1..max_retry {
+        let request = if verify {
+            ctx.http_client.get(url.clone())
+        } else {
+            ctx.http_client.head(url.clone())
+        };
+        let response = match request.send().await {
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => return Probe::Miss,
+            Ok(response) if response.status() == StatusCode::OK => response,
+            Ok(response) => {
                 log::warn!(
                     "query upstream url '{url}', attempt {attempt}/{max_retry} failed: {:?}",
                     response
                 );
+                continue;
+            }
+            Err(e) => {
+                log::warn!("query upstream url '{url}', attempt {attempt}/{max_retry} failed: {e}");
+                continue;
+            }
+        };
+        let headers = response.headers().clone();
+        if !verify {
+            return Probe::Hit(headers, None);
+        }
+        match response.text().await {
+            Ok(text) if is_trusted(trusted_keys, &text) => return Probe::Hit(headers, Some(text)),
+            Ok(_) => {
+                log::warn!("ignored narinfo without trusted signatures from upstream url '{url}'");
+                return Probe::Miss;
+            }
+            Err(e) => {
+                log::warn!("query upstream url '{url}', attempt {attempt}/{max_retry} failed: {e}")
             }
         }
     }
-    Ok(None)
+    Probe::Failed
+}
+
+fn is_trusted(keys: &[NixPublicKey], text: &str) -> bool {
+    let nar_info = match text.parse::<NarInfo>() {
+        Ok(n) => n,
+        Err(e) => {
+            log::debug!("invalid narinfo from upstream: {e}");
+            return false;
+        }
+    };
+    match nar_info.fingerprint() {
+        Ok(fingerprint) => nar_info.sigs.is_trusted(keys, fingerprint.as_bytes()),
+        Err(_) => false,
+    }
+}
+
+fn upstream_timeout(ctx: &ServerContext, upstream: &Upstream) -> Duration {
+    Duration::from_secs(upstream.timeout.unwrap_or(ctx.options.upstream_timeout))
 }
 
 pub fn upstream_url(base: &Url, key: &str) -> Result<Url, Error> {
@@ -63,7 +228,8 @@ pub fn upstream_url(base: &Url, key: &str) -> Result<Url, Error> {
         let mut segments = upstream
             .path_segments_mut()
             .map_err(|_| Error::UpstreamCanNotBeBase(base.clone()))?;
-        segments.push(key);
+        // keys like `nar/{hash}.nar` are paths under the upstream
+        segments.extend(key.split('/'));
     }
     Ok(upstream)
 }
@@ -75,3 +241,134 @@ pub fn redirect_response(key: &str, url: &Url) -> Result<Response<Body>, Error>
         .header(http::header::LOCATION, url.to_string())
         .body(Body::empty())?)
 }
+
+/// Response with a narinfo verified while probing, in both modes
+///
+/// Redirecting or fetching it again would let the upstream replace the verified narinfo.
+fn nar_info_response(
+    key: &str,
+    url: &Url,
+    text: &str,
+    with_body: bool,
+) -> Result<Response<Body>, Error> {
+    log::info!("verified narinfo: key = {key}, url = {url}");
+    let body = if with_body {
+        Body::from(text.to_owned())
+    } else {
+        Body::empty()
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, NARINFO_CONTENT_TYPE)
+        .header(header::CONTENT_LENGTH, text.len())
+        .body(body)?)
+}
+
+/// Fetches the key from the upstream and streams it to the client
+async fn proxy_response(
+    ctx: &ServerContext,
+    key: &str,
+    hit: UpstreamHit<'_>,
+    request_headers: &HeaderMap,
+    with_body: bool,
+) -> Result<Response<Body>, Error> {
+    log::info!("proxy: key = {key}, url = {}", hit.url);
+    if !with_body {
+        return forwarded_response(StatusCode::OK, &hit.headers, Body::empty());
+    }
+    let range = request_headers.get(header::RANGE);
+    // full responses of NARs are shared and cached, their file names are their hashes
+    if let (Some(digest), None) = (nar_digest(key), range) {
+        let fetch = upstream_fetch(ctx, &hit.url);
+        let body = blob_flights::full_body(ctx, &digest, hit.upstream.cache, fetch).await?;
+        let mut response = forwarded_response(StatusCode::OK, &hit.headers, body)?;
+        response
+            .headers_mut()
+            .entry(header::CONTENT_TYPE)
+            .or_insert(HeaderValue::from_static(NAR_CONTENT_TYPE));
+        return Ok(response);
+    }
+    let mut request = ctx.http_client.get(hit.url);
+    if let Some(range) = range {
+        request = request.header(header::RANGE, range);
+    }
+    let response = request.send().await?;
+    let status = response.status();
+    let headers = response.headers().clone();
+    forwarded_response(status, &headers, Body::from_stream(response.bytes_stream()))
+}
+
+/// Fetches a file of an upstream from offsets
+fn upstream_fetch(ctx: &ServerContext, url: &Url) -> Fetch {
+    let client = ctx.http_client.clone();
+    let url = url.clone();
+    Arc::new(move |offset| {
+        let mut request = client.get(url.clone());
+        if offset != 0 {
+            request = request.header(header::RANGE, format!("bytes={offset}-"));
+        }
+        async move {
+            let response = request.send().await?.error_for_status()?;
+            let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+            let stream = response.bytes_stream().map_err(Error::from).boxed();
+            Ok(match (offset, partial) {
+                (0, _) | (_, true) => stream,
+                (_, false) => blob_flights::skip(stream, offset),
+            })
+        }
+        .boxed()
+    })
+}
+
+fn forwarded_response(
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response<Body>, Error> {
+    let mut builder = Response::builder().status(status);
+    for name in FORWARDED_HEADERS {
+        if let Some(value) = headers.get(&name) {
+            builder = builder.header(name, value);
+        }
+    }
+    builder.body(body).map_err(Error::Http)
+}
+
+/// Expected digest of a NAR file, `nar/{nix base32 sha256 of the file}.nar[.{compression}]`
+pub fn nar_digest(key: &str) -> Option<String> {
+    let name = key.strip_prefix("nar/")?;
+    let (hash, _extensions) = name.split_once('.')?;
+    let hash = nix_base32::from_nix_base32(hash)?;
+    if hash.len() != 32 {
+        return None;
+    }
+    Some(format!("sha256:{}", HEXLOWER.encode(&hash)))
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    #[test]
+    fn test_upstream_url() {
+        let base = Url::parse("https://cache.nixos.org/").unwrap();
+        assert_eq!(
+            upstream_url(&base, "nar/abc.nar.xz").unwrap().as_str(),
+            "https://cache.nixos.org/nar/abc.nar.xz"
+        );
+    }
+
+    #[test]
+    fn test_nar_digest() {
+        let hash = Sha256::digest(b"nar");
+        let key = format!("nar/{}.nar.xz", nix_base32::to_nix_base32(&hash));
+        assert_eq!(
+            nar_digest(&key),
+            Some(format!("sha256:{}", HEXLOWER.encode(&hash)))
+        );
+        assert_eq!(nar_digest("nar/invalid.nar"), None);
+        assert_eq!(nar_digest("abc.narinfo"), None);
+    }
+}